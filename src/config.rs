@@ -0,0 +1,838 @@
+//! Startup configuration: defaults, overlaid by an optional YAML or TOML
+//! file, overlaid by environment variables, then validated as a whole.
+
+use crate::exporter::{Compression, ExporterConfig, Protocol};
+use crate::flush::{FlushStrategy, Signal};
+use crate::headers::{HeaderValue, Headers};
+use crate::logs::LogSettings;
+use crate::receiver::ReceiverSettings;
+use crate::retry::RetrySettings;
+use crate::sampling::Sampling;
+use crate::spill::SpillSettings;
+use crate::subscription::{Subscription, TelemetryType};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Names the configuration file when it is not at [`DEFAULT_CONFIG_PATH`].
+pub const CONFIG_PATH_VAR: &str = "MICROFIBER_CONFIG";
+/// Where a layer ships its configuration file.
+pub const DEFAULT_CONFIG_PATH: &str = "/opt/microfiber.yaml";
+/// The service name used when none is configured.
+pub const DEFAULT_SERVICE_NAME: &str = "lambda_extension";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Left unset, [`DEFAULT_SERVICE_NAME`] is used until the function
+    /// reports its own through the receiver.
+    pub service_name: Option<String>,
+    pub resource_attributes: BTreeMap<String, String>,
+    pub exporter: ExporterSettings,
+    /// Further destinations, configured like `exporter`. Each one gets the
+    /// same telemetry, exported independently of the others.
+    pub destinations: Vec<ExporterSettings>,
+    pub signals: EnabledSignals,
+    pub sampling: Sampling,
+    pub logs: LogSettings,
+    pub flush: FlushStrategy,
+    pub telemetry: Subscription,
+    /// Keeps spans and logs unsent at shutdown on disk for the next INIT.
+    pub spill: SpillSettings,
+    /// Accepts OTLP from the function on localhost.
+    pub receiver: ReceiverSettings,
+}
+
+/// The OTLP destination, following the OTLP exporter specification: the
+/// shared `endpoint` is a base URL that gets the signal's path appended for
+/// the HTTP protocols, while per-signal endpoints are used as given. Other
+/// per-signal settings override the shared ones, and per-signal headers are
+/// added to the shared headers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExporterSettings {
+    /// Names the destination in logs; unnamed ones go by their position.
+    pub name: Option<String>,
+    /// The signals sent to this destination.
+    pub signals: EnabledSignals,
+    pub protocol: Protocol,
+    pub endpoint: Option<String>,
+    pub headers: Headers,
+    pub timeout_ms: u64,
+    pub compression: Compression,
+    pub retry: RetrySettings,
+    pub traces: SignalExporterSettings,
+    pub metrics: SignalExporterSettings,
+    pub logs: SignalExporterSettings,
+}
+
+impl Default for ExporterSettings {
+    fn default() -> Self {
+        ExporterSettings {
+            name: None,
+            signals: EnabledSignals::default(),
+            protocol: Protocol::default(),
+            endpoint: None,
+            headers: Headers::default(),
+            timeout_ms: 10_000,
+            compression: Compression::default(),
+            retry: RetrySettings::default(),
+            traces: SignalExporterSettings::default(),
+            metrics: SignalExporterSettings::default(),
+            logs: SignalExporterSettings::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalExporterSettings {
+    pub protocol: Option<Protocol>,
+    pub endpoint: Option<String>,
+    pub headers: Headers,
+    pub timeout_ms: Option<u64>,
+    pub compression: Option<Compression>,
+}
+
+impl ExporterSettings {
+    /// Where and how `signal` is exported to this destination, named
+    /// `destination`.
+    fn resolve(&self, signal: Signal, destination: String) -> ExporterConfig {
+        let settings = self.signal(signal);
+        let protocol = settings.protocol.unwrap_or(self.protocol);
+        let endpoint = match &settings.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => {
+                let base = self
+                    .endpoint
+                    .as_deref()
+                    .unwrap_or_else(|| protocol.default_endpoint());
+                if protocol.is_http() {
+                    format!("{}{}", base.trim_end_matches('/'), http_path(signal))
+                } else {
+                    base.to_string()
+                }
+            }
+        };
+        let mut headers = self.headers.resolved().clone();
+        headers.extend(settings.headers.resolved().clone());
+        let mut secret_headers = self.headers.references().clone();
+        secret_headers.retain(|name, _| !settings.headers.resolved().contains_key(name));
+        secret_headers.extend(settings.headers.references().clone());
+        ExporterConfig {
+            destination,
+            signal,
+            protocol,
+            endpoint,
+            headers,
+            secret_headers,
+            timeout: Duration::from_millis(settings.timeout_ms.unwrap_or(self.timeout_ms)),
+            compression: settings.compression.unwrap_or(self.compression),
+            retry: self.retry,
+        }
+    }
+
+    /// Resolves header values and adds any problems with these settings,
+    /// found at `path` in the configuration, to `problems`.
+    async fn validate(&mut self, path: &str, problems: &mut Vec<String>) {
+        if let Some(endpoint) = &self.endpoint {
+            validate_endpoint(&format!("{}.endpoint", path), endpoint, problems);
+        }
+        problems.extend(self.headers.resolve(&format!("{}.headers", path)).await);
+        if self.timeout_ms == 0 {
+            problems.push(format!("{}.timeout_ms must be greater than 0", path));
+        }
+        problems.extend(self.retry.validate(&format!("{}.retry", path)));
+        for signal in [Signal::Traces, Signal::Metrics, Signal::Logs] {
+            let settings = self.signal_mut(signal);
+            problems.extend(
+                settings
+                    .headers
+                    .resolve(&format!("{}.{}.headers", path, signal))
+                    .await,
+            );
+            if let Some(endpoint) = &settings.endpoint {
+                let name = format!("{}.{}.endpoint", path, signal);
+                validate_endpoint(&name, endpoint, problems);
+            }
+            if settings.timeout_ms == Some(0) {
+                problems.push(format!(
+                    "{}.{}.timeout_ms must be greater than 0",
+                    path, signal
+                ));
+            }
+        }
+    }
+
+    fn signal(&self, signal: Signal) -> &SignalExporterSettings {
+        match signal {
+            Signal::Traces => &self.traces,
+            Signal::Metrics => &self.metrics,
+            Signal::Logs => &self.logs,
+        }
+    }
+
+    fn signal_mut(&mut self, signal: Signal) -> &mut SignalExporterSettings {
+        match signal {
+            Signal::Traces => &mut self.traces,
+            Signal::Metrics => &mut self.metrics,
+            Signal::Logs => &mut self.logs,
+        }
+    }
+}
+
+/// Signals that are exported. A disabled signal still gets a provider, just
+/// one without an exporter.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnabledSignals {
+    pub traces: bool,
+    pub metrics: bool,
+    pub logs: bool,
+}
+
+impl EnabledSignals {
+    pub fn includes(&self, signal: Signal) -> bool {
+        match signal {
+            Signal::Traces => self.traces,
+            Signal::Metrics => self.metrics,
+            Signal::Logs => self.logs,
+        }
+    }
+}
+
+impl Default for EnabledSignals {
+    fn default() -> Self {
+        EnabledSignals {
+            traces: true,
+            metrics: true,
+            logs: true,
+        }
+    }
+}
+
+impl Config {
+    /// The configured service name, or the default.
+    pub fn service_name(&self) -> &str {
+        self.service_name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME)
+    }
+
+    /// Whether a service name was configured in the file or environment,
+    /// rather than left for the function's own to replace.
+    pub fn service_name_configured(&self) -> bool {
+        self.service_name.is_some()
+    }
+
+    /// Where `signal` is exported: one entry per destination it is sent
+    /// to, and none when the signal is disabled.
+    pub fn exporters(&self, signal: Signal) -> Vec<ExporterConfig> {
+        if !self.signals.includes(signal) {
+            return Vec::new();
+        }
+        self.destinations()
+            .filter(|(_, settings)| settings.signals.includes(signal))
+            .map(|(name, settings)| settings.resolve(signal, name))
+            .collect()
+    }
+
+    // `exporter` followed by `destinations`, each with its name.
+    fn destinations(&self) -> impl Iterator<Item = (String, &ExporterSettings)> {
+        std::iter::once(&self.exporter)
+            .chain(&self.destinations)
+            .enumerate()
+            .map(|(i, settings)| {
+                let name = settings.name.clone().unwrap_or_else(|| destination_path(i));
+                (name, settings)
+            })
+    }
+
+    /// Overlays environment variables: first the names that predate the
+    /// configuration file, then the standard `OTEL_*` ones, which take
+    /// precedence over both.
+    fn apply_env(&mut self, vars: &Vars) -> Result<(), ConfigError> {
+        if let Some(endpoint) = vars.get("COLLECTOR_ENDPOINT") {
+            self.exporter.endpoint = Some(endpoint.to_string());
+        }
+        if let Some(service_name) = vars.get("SERVICE_NAME") {
+            self.service_name = Some(service_name.to_string());
+        }
+        if let Some(flush) = vars.parse("FLUSH_STRATEGY")? {
+            self.flush = flush;
+        }
+        if let Some(value) = vars.get("TELEMETRY_TYPES") {
+            let mut types = Vec::new();
+            for name in value.split(',').filter(|name| !name.trim().is_empty()) {
+                let telemetry_type: TelemetryType =
+                    name.parse().map_err(|message| ConfigError::Env {
+                        name: "TELEMETRY_TYPES".to_string(),
+                        message,
+                    })?;
+                if !types.contains(&telemetry_type) {
+                    types.push(telemetry_type);
+                }
+            }
+            self.telemetry.types = types;
+        }
+        let buffering = &mut self.telemetry.buffering;
+        if let Some(timeout_ms) = vars.parse("TELEMETRY_BUFFERING_TIMEOUT_MS")? {
+            buffering.timeout_ms = timeout_ms;
+        }
+        if let Some(max_bytes) = vars.parse("TELEMETRY_BUFFERING_MAX_BYTES")? {
+            buffering.max_bytes = max_bytes;
+        }
+        if let Some(max_items) = vars.parse("TELEMETRY_BUFFERING_MAX_ITEMS")? {
+            buffering.max_items = max_items;
+        }
+
+        self.apply_otel_env(vars)
+    }
+
+    fn apply_otel_env(&mut self, vars: &Vars) -> Result<(), ConfigError> {
+        let exporter = &mut self.exporter;
+        if let Some(protocol) = vars.parse("OTEL_EXPORTER_OTLP_PROTOCOL")? {
+            exporter.protocol = protocol;
+        }
+        if let Some(endpoint) = vars.get("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            exporter.endpoint = Some(endpoint.to_string());
+        }
+        for (name, value) in vars
+            .key_values("OTEL_EXPORTER_OTLP_HEADERS")?
+            .unwrap_or_default()
+        {
+            exporter.headers.insert(name, HeaderValue::Value(value));
+        }
+        if let Some(timeout_ms) = vars.parse("OTEL_EXPORTER_OTLP_TIMEOUT")? {
+            exporter.timeout_ms = timeout_ms;
+        }
+        if let Some(compression) = vars.parse("OTEL_EXPORTER_OTLP_COMPRESSION")? {
+            exporter.compression = compression;
+        }
+        for signal in [Signal::Traces, Signal::Metrics, Signal::Logs] {
+            let prefix = format!(
+                "OTEL_EXPORTER_OTLP_{}_",
+                signal.to_string().to_ascii_uppercase()
+            );
+            let settings = exporter.signal_mut(signal);
+            if let Some(protocol) = vars.parse(&format!("{}PROTOCOL", prefix))? {
+                settings.protocol = Some(protocol);
+            }
+            if let Some(endpoint) = vars.get(&format!("{}ENDPOINT", prefix)) {
+                settings.endpoint = Some(endpoint.to_string());
+            }
+            for (name, value) in vars
+                .key_values(&format!("{}HEADERS", prefix))?
+                .unwrap_or_default()
+            {
+                settings.headers.insert(name, HeaderValue::Value(value));
+            }
+            if let Some(timeout_ms) = vars.parse(&format!("{}TIMEOUT", prefix))? {
+                settings.timeout_ms = Some(timeout_ms);
+            }
+            if let Some(compression) = vars.parse(&format!("{}COMPRESSION", prefix))? {
+                settings.compression = Some(compression);
+            }
+        }
+
+        // OTEL_SERVICE_NAME beats a service.name in OTEL_RESOURCE_ATTRIBUTES,
+        // and both beat SERVICE_NAME.
+        if let Some(attributes) = vars.key_values("OTEL_RESOURCE_ATTRIBUTES")? {
+            self.resource_attributes.extend(attributes);
+            if let Some(service_name) = self.resource_attributes.get("service.name") {
+                self.service_name = Some(service_name.clone());
+            }
+        }
+        if let Some(service_name) = vars.get("OTEL_SERVICE_NAME") {
+            self.service_name = Some(service_name.to_string());
+        }
+
+        if let Some(sampler) = vars.parse("OTEL_TRACES_SAMPLER")? {
+            self.sampling.sampler = sampler;
+        }
+        if let Some(ratio) = vars.parse("OTEL_TRACES_SAMPLER_ARG")? {
+            self.sampling.ratio = ratio;
+        }
+        Ok(())
+    }
+
+    /// Resolves header values and checks the merged configuration,
+    /// reporting every problem at once.
+    async fn validate(&mut self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.service_name().trim().is_empty() {
+            problems.push("service_name must not be empty".to_string());
+        }
+        for (i, settings) in std::iter::once(&mut self.exporter)
+            .chain(&mut self.destinations)
+            .enumerate()
+        {
+            settings.validate(&destination_path(i), &mut problems).await;
+        }
+        let mut names = BTreeSet::new();
+        for (name, _) in self.destinations() {
+            if !names.insert(name.clone()) {
+                problems.push(format!("more than one destination is named {:?}", name));
+            }
+        }
+        problems.extend(self.sampling.validate());
+        problems.extend(self.telemetry.validate());
+        problems.extend(self.spill.validate());
+        problems.extend(self.receiver.validate());
+        for signal in [Signal::Traces, Signal::Metrics, Signal::Logs] {
+            for exporter in self.exporters(signal) {
+                if self.sends_to_receiver(&exporter.endpoint) {
+                    problems.push(format!(
+                        "{} for {} are exported to {}, which is the OTLP receiver",
+                        signal, exporter.destination, exporter.endpoint
+                    ));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        for signal in [Signal::Traces, Signal::Metrics, Signal::Logs] {
+            for exporter in self.exporters(signal) {
+                if exporter.protocol.is_http() && exporter.endpoint.contains(":4317") {
+                    warn!(
+                        "Exporting {} to {} as {} at {}, which is the default gRPC port",
+                        signal, exporter.destination, exporter.protocol, exporter.endpoint
+                    );
+                }
+                if self.spill.enabled && signal != Signal::Metrics && !exporter.protocol.is_http() {
+                    warn!(
+                        "Unsent {} cannot be spilled for {}, which uses gRPC",
+                        signal, exporter.destination
+                    );
+                }
+            }
+        }
+        if !self.telemetry.includes(TelemetryType::Platform) {
+            warn!("Platform events are not subscribed to, so no spans or invocation metrics will be produced");
+        }
+        Ok(())
+    }
+
+    // Exporting to the receiver would send the function's telemetry round in
+    // a loop.
+    fn sends_to_receiver(&self, endpoint: &str) -> bool {
+        let Ok(uri) = endpoint.parse::<http::Uri>() else {
+            return false;
+        };
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        uri.host()
+            .is_some_and(|host| self.receiver.receives(host, port))
+    }
+}
+
+// Settings the OTLP exporters also read from OTEL_EXPORTER_OTLP_* and
+// OTEL_EXPORTER_OTLP_{TRACES,METRICS,LOGS}_*.
+const OTEL_EXPORTER_VARS: [&str; 5] = ["PROTOCOL", "ENDPOINT", "HEADERS", "TIMEOUT", "COMPRESSION"];
+
+fn http_path(signal: Signal) -> &'static str {
+    match signal {
+        Signal::Traces => "/v1/traces",
+        Signal::Metrics => "/v1/metrics",
+        Signal::Logs => "/v1/logs",
+    }
+}
+
+fn destination_path(index: usize) -> String {
+    match index {
+        0 => "exporter".to_string(),
+        i => format!("destinations[{}]", i - 1),
+    }
+}
+
+fn validate_endpoint(name: &str, endpoint: &str, problems: &mut Vec<String>) {
+    if !(endpoint.starts_with("http://") || endpoint.starts_with("https://")) {
+        problems.push(format!(
+            "{} is {:?}, expected an http:// or https:// URL",
+            name, endpoint
+        ));
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, source: io::Error },
+    Parse { path: PathBuf, message: String },
+    Env { name: String, message: String },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "cannot parse {}: {}", path.display(), message)
+            }
+            ConfigError::Env { name, message } => write!(f, "{}: {}", name, message),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Loads the configuration file, if there is one, overlays the environment
+/// variables in `vars` and validates the result, fetching any referenced
+/// secrets.
+pub async fn load(vars: &Vars) -> Result<Config, ConfigError> {
+    let mut config = match config_path() {
+        Some(path) => {
+            info!("Reading configuration from {}", path.display());
+            read_file(&path)?
+        }
+        None => Config::default(),
+    };
+    config.apply_env(vars)?;
+    config.validate().await?;
+    debug!("Loaded configuration: {:?}", config);
+    Ok(config)
+}
+
+// An explicitly named file must exist; the default one is optional.
+fn config_path() -> Option<PathBuf> {
+    match env::var_os(CONFIG_PATH_VAR) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let path = Path::new(DEFAULT_CONFIG_PATH);
+            path.exists().then(|| path.to_path_buf())
+        }
+    }
+}
+
+// TOML is picked by extension; anything else is read as YAML.
+fn read_file(path: &Path) -> Result<Config, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let parsed = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|message| ConfigError::Parse {
+        path: path.to_path_buf(),
+        message,
+    })
+}
+
+/// The environment variables the configuration is read from, taken while
+/// the process is still single-threaded.
+#[derive(Debug, Default)]
+pub struct Vars(BTreeMap<String, String>);
+
+impl Vars {
+    /// Takes the environment, removing the `OTEL_EXPORTER_OTLP_*` variables
+    /// from it: the OTLP exporters read them too and would let them override
+    /// what was resolved from them (reading the timeouts as seconds). Must be
+    /// called before any other thread starts, since those may read the
+    /// environment while it changes.
+    pub fn take() -> Self {
+        let vars = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        for name in OTEL_EXPORTER_VARS {
+            for signal in ["", "TRACES_", "METRICS_", "LOGS_"] {
+                env::remove_var(format!("OTEL_EXPORTER_OTLP_{}{}", signal, name));
+            }
+        }
+        Vars(vars)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    fn parse<T>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get(name) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| ConfigError::Env {
+                    name: name.to_string(),
+                    message: format!("cannot parse {:?}: {}", value, e),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    fn key_values(&self, name: &str) -> Result<Option<Vec<(String, String)>>, ConfigError> {
+        match self.get(name) {
+            Some(value) => parse_key_values(value)
+                .map(Some)
+                .map_err(|message| ConfigError::Env {
+                    name: name.to_string(),
+                    message,
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parses a `key1=value1,key2=value2` list with percent-encoded values, the
+/// format of `OTEL_EXPORTER_OTLP_HEADERS` and `OTEL_RESOURCE_ATTRIBUTES`.
+fn parse_key_values(list: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let Some((key, value)) = entry.split_once('=') else {
+            return Err(format!("{:?} is not a key=value pair", entry));
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("{:?} has an empty key", entry));
+        }
+        pairs.push((key.to_string(), percent_decode(value.trim())?));
+    }
+    Ok(pairs)
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                .ok_or_else(|| format!("invalid percent-encoding in {:?}", value))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("{:?} does not decode to UTF-8", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies `vars` to `config` as the only variables set.
+    fn apply(config: &mut Config, vars: &[(&str, &str)]) -> Result<(), ConfigError> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        config.apply_env(&Vars(vars))
+    }
+
+    fn from_file() -> Config {
+        serde_yaml::from_str("service_name: from-file\nexporter:\n  endpoint: http://file:4318\n")
+            .unwrap()
+    }
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn percent_decodes_values() {
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert_eq!(percent_decode("a%20b%2Cc%3d").unwrap(), "a b,c=");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("100%25").unwrap(), "100%");
+    }
+
+    #[test]
+    fn rejects_invalid_percent_encoding() {
+        for value in ["%zz", "%", "abc%2", "%g0", "%+1"] {
+            assert!(percent_decode(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_values_that_do_not_decode_to_utf8() {
+        assert!(percent_decode("%ff%fe").is_err());
+        assert!(percent_decode("%C3").is_err());
+    }
+
+    #[test]
+    fn parses_key_value_lists() {
+        assert_eq!(
+            parse_key_values(" a = 1 ,b=x%3Dy,c=, d=e=f").unwrap(),
+            pairs(&[("a", "1"), ("b", "x=y"), ("c", ""), ("d", "e=f")])
+        );
+    }
+
+    #[test]
+    fn skips_empty_entries() {
+        assert_eq!(parse_key_values("").unwrap(), pairs(&[]));
+        assert_eq!(
+            parse_key_values(",a=1,, ,b=2,").unwrap(),
+            pairs(&[("a", "1"), ("b", "2")])
+        );
+    }
+
+    #[test]
+    fn rejects_empty_keys_and_entries_without_a_value() {
+        assert!(parse_key_values("=1").is_err());
+        assert!(parse_key_values("a=1, =2").is_err());
+        assert!(parse_key_values("a=1,b").is_err());
+        assert!(parse_key_values("a=%zz").is_err());
+    }
+
+    #[test]
+    fn file_settings_apply_without_variables() {
+        let mut config = from_file();
+        apply(&mut config, &[]).unwrap();
+        assert_eq!(config.service_name(), "from-file");
+        assert_eq!(
+            config.exporter.endpoint.as_deref(),
+            Some("http://file:4318")
+        );
+    }
+
+    #[test]
+    fn legacy_variables_override_the_file() {
+        let mut config = from_file();
+        apply(
+            &mut config,
+            &[
+                ("COLLECTOR_ENDPOINT", "http://legacy:4318"),
+                ("SERVICE_NAME", "from-legacy"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.service_name(), "from-legacy");
+        assert_eq!(
+            config.exporter.endpoint.as_deref(),
+            Some("http://legacy:4318")
+        );
+    }
+
+    #[test]
+    fn otel_variables_override_legacy_ones_and_the_file() {
+        let mut config = from_file();
+        apply(
+            &mut config,
+            &[
+                ("COLLECTOR_ENDPOINT", "http://legacy:4318"),
+                ("SERVICE_NAME", "from-legacy"),
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://otel:4318"),
+                ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "http://traces:4318/t"),
+                ("OTEL_SERVICE_NAME", "from-otel"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.service_name(), "from-otel");
+        assert_eq!(
+            config.exporter.endpoint.as_deref(),
+            Some("http://otel:4318")
+        );
+        assert_eq!(
+            config.exporters(Signal::Traces)[0].endpoint,
+            "http://traces:4318/t"
+        );
+        assert_eq!(
+            config.exporters(Signal::Logs)[0].endpoint,
+            "http://otel:4318/v1/logs"
+        );
+    }
+
+    #[test]
+    fn otel_service_name_beats_resource_attributes() {
+        let mut config = from_file();
+        apply(
+            &mut config,
+            &[
+                ("SERVICE_NAME", "from-legacy"),
+                (
+                    "OTEL_RESOURCE_ATTRIBUTES",
+                    "service.name=from-attributes,team=a",
+                ),
+                ("OTEL_SERVICE_NAME", "from-otel"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.service_name(), "from-otel");
+        assert_eq!(config.resource_attributes["team"], "a");
+    }
+
+    #[test]
+    fn resource_attributes_service_name_beats_legacy_service_name() {
+        let mut config = from_file();
+        apply(
+            &mut config,
+            &[
+                ("SERVICE_NAME", "from-legacy"),
+                ("OTEL_RESOURCE_ATTRIBUTES", "service.name=from%20attributes"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.service_name(), "from attributes");
+    }
+
+    #[tokio::test]
+    async fn otel_headers_are_percent_decoded_and_added() {
+        let mut config = from_file();
+        apply(
+            &mut config,
+            &[("OTEL_EXPORTER_OTLP_HEADERS", "x-api-key=a%2Fb,x-team=t")],
+        )
+        .unwrap();
+        assert!(config.exporter.headers.resolve("headers").await.is_empty());
+        let headers = config.exporters(Signal::Traces).remove(0).headers;
+        assert_eq!(headers["x-api-key"], "a/b");
+        assert_eq!(headers["x-team"], "t");
+    }
+
+    #[test]
+    fn malformed_variables_are_reported_by_name() {
+        let mut config = from_file();
+        let error = apply(
+            &mut config,
+            &[("OTEL_RESOURCE_ATTRIBUTES", "service.name=%zz")],
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("OTEL_RESOURCE_ATTRIBUTES"));
+    }
+
+    #[test]
+    fn tells_a_configured_default_service_name_from_none() {
+        let mut config = Config::default();
+        apply(&mut config, &[]).unwrap();
+        assert!(!config.service_name_configured());
+        assert_eq!(config.service_name(), DEFAULT_SERVICE_NAME);
+
+        let config: Config = serde_yaml::from_str("service_name: lambda_extension\n").unwrap();
+        assert!(config.service_name_configured());
+
+        let mut config = Config::default();
+        apply(&mut config, &[("OTEL_SERVICE_NAME", DEFAULT_SERVICE_NAME)]).unwrap();
+        assert!(config.service_name_configured());
+        assert_eq!(config.service_name(), DEFAULT_SERVICE_NAME);
+    }
+}
@@ -0,0 +1,465 @@
+use crate::flush::Signal;
+use crate::metrics::ExportMetrics;
+use crate::retry::RetrySettings;
+use crate::secrets::{self, SecretRef};
+use crate::spill::{self, Spool};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use http::header::{HeaderName, HeaderValue, CONTENT_ENCODING, RETRY_AFTER};
+use http::{HeaderMap, StatusCode};
+use opentelemetry::KeyValue;
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{
+    HttpExporterBuilder, LogExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder,
+    TonicExporterBuilder, WithExportConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tonic::metadata::{Ascii, MetadataKey, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::ClientTlsConfig;
+use tonic::Status;
+
+/// OTLP transport, named as in `OTEL_EXPORTER_OTLP_PROTOCOL`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Protocol {
+    #[serde(rename = "grpc")]
+    Grpc,
+    #[default]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    #[serde(rename = "http/json")]
+    HttpJson,
+}
+
+impl Protocol {
+    pub fn default_endpoint(self) -> &'static str {
+        match self {
+            Protocol::Grpc => "http://localhost:4317",
+            Protocol::HttpProtobuf | Protocol::HttpJson => "http://localhost:4318",
+        }
+    }
+
+    pub fn is_http(self) -> bool {
+        !matches!(self, Protocol::Grpc)
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "grpc" => Ok(Protocol::Grpc),
+            "http/protobuf" => Ok(Protocol::HttpProtobuf),
+            "http/json" => Ok(Protocol::HttpJson),
+            other => Err(format!(
+                "unsupported OTLP protocol {:?}, expected grpc, http/protobuf or http/json",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Grpc => "grpc",
+            Protocol::HttpProtobuf => "http/protobuf",
+            Protocol::HttpJson => "http/json",
+        })
+    }
+}
+
+/// Request body compression, named as in `OTEL_EXPORTER_OTLP_COMPRESSION`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!(
+                "unsupported compression {:?}, expected none, gzip or zstd",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Where and how one signal is exported, with every setting resolved.
+#[derive(Clone, Debug)]
+pub struct ExporterConfig {
+    /// The destination's name, for logs.
+    pub destination: String,
+    pub signal: Signal,
+    pub protocol: Protocol,
+    /// The full URL for HTTP, or the collector address for gRPC.
+    pub endpoint: String,
+    pub headers: BTreeMap<String, String>,
+    /// Headers whose current value is read from the secret cache on every
+    /// export, replacing the value in `headers`.
+    pub secret_headers: BTreeMap<String, SecretRef>,
+    pub timeout: Duration,
+    pub compression: Compression,
+    pub retry: RetrySettings,
+}
+
+/// A tonic or HTTP exporter builder for a single signal, convertible into the
+/// per-signal builder types the OTLP pipelines take.
+#[allow(clippy::large_enum_variant)]
+pub enum ExporterBuilder {
+    Tonic(TonicExporterBuilder),
+    Http(HttpExporterBuilder),
+}
+
+impl ExporterBuilder {
+    /// HTTP exports are written to `spool` instead of sent while
+    /// [`spill::capture`] runs them, and replay what it holds first.
+    pub fn new(config: &ExporterConfig, spool: Option<Arc<Spool>>) -> Self {
+        let attributes = vec![
+            KeyValue::new("destination", config.destination.clone()),
+            KeyValue::new("signal", config.signal.to_string()),
+            KeyValue::new("encoding", config.compression.to_string()),
+        ];
+        match config.protocol {
+            Protocol::Grpc => {
+                let mut builder = opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&config.endpoint)
+                    .with_timeout(config.timeout);
+                if config.endpoint.starts_with("https://") {
+                    builder = builder.with_tls_config(ClientTlsConfig::new().with_native_roots());
+                }
+                if !config.headers.is_empty() {
+                    let mut metadata = MetadataMap::new();
+                    insert_metadata(&mut metadata, &config.headers);
+                    builder = builder.with_metadata(metadata);
+                }
+                match config.compression {
+                    Compression::None => {}
+                    Compression::Gzip => {
+                        builder = builder.with_compression(opentelemetry_otlp::Compression::Gzip)
+                    }
+                    Compression::Zstd => {
+                        builder = builder.with_compression(opentelemetry_otlp::Compression::Zstd)
+                    }
+                }
+                builder = builder.with_interceptor(ExportInterceptor {
+                    secret_headers: config.secret_headers.clone(),
+                    attributes,
+                });
+                ExporterBuilder::Tonic(builder)
+            }
+            Protocol::HttpProtobuf | Protocol::HttpJson => {
+                let protocol = match config.protocol {
+                    Protocol::HttpJson => opentelemetry_otlp::Protocol::HttpJson,
+                    _ => opentelemetry_otlp::Protocol::HttpBinary,
+                };
+                ExporterBuilder::Http(
+                    opentelemetry_otlp::new_exporter()
+                        .http()
+                        .with_protocol(protocol)
+                        .with_endpoint(&config.endpoint)
+                        .with_timeout(config.timeout)
+                        .with_headers(config.headers.clone().into_iter().collect())
+                        .with_http_client(ExportClient {
+                            client: reqwest::Client::new(),
+                            timeout: config.timeout,
+                            compression: config.compression,
+                            secret_headers: config.secret_headers.clone(),
+                            spool,
+                            attributes,
+                        }),
+                )
+            }
+        }
+    }
+}
+
+// zstd's default, which compresses better than gzip at a similar speed.
+const ZSTD_LEVEL: i32 = 3;
+
+// The OTLP HTTP exporter cannot compress request bodies itself, only
+// takes headers that are fixed when it is built, and leaves the timeout to
+// the client it is given.
+#[derive(Debug)]
+struct ExportClient {
+    client: reqwest::Client,
+    timeout: Duration,
+    compression: Compression,
+    secret_headers: BTreeMap<String, SecretRef>,
+    spool: Option<Arc<Spool>>,
+    // Tag the request size metrics.
+    attributes: Vec<KeyValue>,
+}
+
+#[async_trait]
+impl HttpClient for ExportClient {
+    async fn send(&self, mut request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        if let Some(spool) = &self.spool {
+            if spill::capturing() {
+                spool.write(&self.compress(request)?)?;
+                return Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Bytes::new())?);
+            }
+            spool.replay().await;
+        }
+        insert_headers(request.headers_mut(), &current_values(&self.secret_headers));
+        let uncompressed = request.body().len();
+        let request = self.compress(request)?;
+        ExportMetrics::global().record(uncompressed, request.body().len(), &self.attributes);
+        let mut request: reqwest::Request = request.try_into()?;
+        *request.timeout_mut() = Some(self.timeout);
+        let response = self.client.execute(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                && !self.secret_headers.is_empty()
+            {
+                secrets::global().refresh();
+            }
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            return Err(Box::new(StatusError {
+                status,
+                retry_after,
+            }));
+        }
+        let headers = response.headers().clone();
+        let mut http_response = Response::builder()
+            .status(status)
+            .body(response.bytes().await?)?;
+        *http_response.headers_mut() = headers;
+        Ok(http_response)
+    }
+}
+
+impl ExportClient {
+    fn compress(&self, request: Request<Vec<u8>>) -> Result<Request<Vec<u8>>, HttpError> {
+        let (mut parts, body) = request.into_parts();
+        let (encoding, body) = match self.compression {
+            Compression::None => return Ok(Request::from_parts(parts, body)),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                ("gzip", encoder.finish()?)
+            }
+            Compression::Zstd => ("zstd", zstd::encode_all(body.as_slice(), ZSTD_LEVEL)?),
+        };
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        Ok(Request::from_parts(parts, body))
+    }
+}
+
+/// An HTTP export the collector answered with an error status.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    /// How long the collector asked to be left alone, from `Retry-After`.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the collector responded with {}", self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// Retry-After is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value.trim())
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+// Counts every gRPC request and adds the current secret header values to it,
+// replacing the ones the exporter was built with.
+#[derive(Clone)]
+struct ExportInterceptor {
+    secret_headers: BTreeMap<String, SecretRef>,
+    // Tag the request count.
+    attributes: Vec<KeyValue>,
+}
+
+impl Interceptor for ExportInterceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        ExportMetrics::global().record_request(&self.attributes);
+        if !self.secret_headers.is_empty() {
+            insert_metadata(
+                request.metadata_mut(),
+                &current_values(&self.secret_headers),
+            );
+        }
+        Ok(request)
+    }
+}
+
+/// The latest fetched value of each secret header.
+pub fn current_values(secret_headers: &BTreeMap<String, SecretRef>) -> BTreeMap<String, String> {
+    secret_headers
+        .iter()
+        .filter_map(|(name, reference)| {
+            let value = secrets::global().cached(reference)?;
+            Some((name.clone(), value))
+        })
+        .collect()
+}
+
+impl From<ExporterBuilder> for SpanExporterBuilder {
+    fn from(builder: ExporterBuilder) -> Self {
+        match builder {
+            ExporterBuilder::Tonic(builder) => builder.into(),
+            ExporterBuilder::Http(builder) => builder.into(),
+        }
+    }
+}
+
+impl From<ExporterBuilder> for MetricsExporterBuilder {
+    fn from(builder: ExporterBuilder) -> Self {
+        match builder {
+            ExporterBuilder::Tonic(builder) => builder.into(),
+            ExporterBuilder::Http(builder) => builder.into(),
+        }
+    }
+}
+
+impl From<ExporterBuilder> for LogExporterBuilder {
+    fn from(builder: ExporterBuilder) -> Self {
+        match builder {
+            ExporterBuilder::Tonic(builder) => builder.into(),
+            ExporterBuilder::Http(builder) => builder.into(),
+        }
+    }
+}
+
+/// Adds `headers` to an HTTP request, replacing any with the same name.
+pub fn insert_headers(map: &mut HeaderMap, headers: &BTreeMap<String, String>) {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            map.insert(name, value);
+        }
+    }
+}
+
+// Headers are validated with the rest of the configuration, so anything that
+// does not fit gRPC metadata has already been rejected.
+fn insert_metadata(metadata: &mut MetadataMap, headers: &BTreeMap<String, String>) {
+    for (name, value) in headers {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(name.as_bytes()),
+            MetadataValue::try_from(value.as_str()),
+        ) {
+            metadata.insert(key, value);
+        }
+    }
+}
+
+/// Whether `name: value` can be sent both as an HTTP header and as gRPC
+/// metadata.
+pub fn validate_header(name: &str, value: &str) -> Result<(), String> {
+    MetadataKey::<Ascii>::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name))?;
+    MetadataValue::<Ascii>::try_from(value)
+        .map_err(|_| format!("invalid value for header {:?}", name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    fn client(timeout: Duration) -> ExportClient {
+        ExportClient {
+            client: reqwest::Client::new(),
+            timeout,
+            compression: Compression::None,
+            secret_headers: BTreeMap::new(),
+            spool: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+
+        let later = SystemTime::now() + Duration::from_secs(90);
+        let waited = parse_retry_after(&httpdate::fmt_http_date(later)).unwrap();
+        assert!(waited > Duration::from_secs(85) && waited <= Duration::from_secs(90));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+
+        for garbage in ["", "soon", "-5", "1.5", "Wed, 99 Oct"] {
+            assert_eq!(parse_retry_after(garbage), None, "{:?}", garbage);
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_collector_that_never_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let hung = tokio::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(connection);
+        });
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://{}/v1/traces", address))
+            .body(b"spans".to_vec())
+            .unwrap();
+        let started = Instant::now();
+        let error = client(Duration::from_millis(200))
+            .send(request)
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let error = error.downcast_ref::<reqwest::Error>().unwrap();
+        assert!(error.is_timeout(), "{}", error);
+        hung.abort();
+    }
+}
@@ -0,0 +1,263 @@
+use crate::pipeline::Pipelines;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+// Time kept back from the invocation deadline for the flush itself when
+// waiting for the runtime to finish.
+const FLUSH_RESERVE: Duration = Duration::from_millis(500);
+
+/// When buffered telemetry is pushed to the collector. Lambda freezes the
+/// execution environment as soon as every extension has asked for the next
+/// event, so flushing only ever happens at the end of an invocation; the
+/// strategy decides which invocations pay for it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum FlushStrategy {
+    /// After every invocation.
+    #[default]
+    End,
+    /// After every N-th invocation.
+    Invocations(u32),
+    /// After the first invocation to end at least this long after the
+    /// previous flush.
+    Period(Duration),
+}
+
+impl FromStr for FlushStrategy {
+    type Err = String;
+
+    /// Parses `end`, `invocations:N` or `period:SECONDS`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            format!(
+                "unsupported flush strategy {:?}, expected end, invocations:N or period:SECONDS",
+                s
+            )
+        };
+        match s.split_once(':') {
+            None if s == "end" => Ok(FlushStrategy::End),
+            Some(("invocations", n)) => match n.trim().parse() {
+                Ok(n) if n > 0 => Ok(FlushStrategy::Invocations(n)),
+                _ => Err(invalid()),
+            },
+            Some(("period", seconds)) => match seconds.trim().parse::<u64>() {
+                Ok(seconds) if seconds > 0 => {
+                    Ok(FlushStrategy::Period(Duration::from_secs(seconds)))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for FlushStrategy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FlushStrategy> for String {
+    fn from(strategy: FlushStrategy) -> Self {
+        strategy.to_string()
+    }
+}
+
+impl fmt::Display for FlushStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushStrategy::End => f.write_str("end"),
+            FlushStrategy::Invocations(n) => write!(f, "invocations:{}", n),
+            FlushStrategy::Period(period) => write!(f, "period:{}", period.as_secs()),
+        }
+    }
+}
+
+/// A telemetry signal with its own provider and export pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Traces,
+    Metrics,
+    Logs,
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Signal::Traces => "traces",
+            Signal::Metrics => "metrics",
+            Signal::Logs => "logs",
+        })
+    }
+}
+
+/// Flushes telemetry from the events processor according to a
+/// [`FlushStrategy`].
+pub struct Flusher {
+    strategy: FlushStrategy,
+    pipelines: Pipelines,
+    runtime_done: Option<watch::Receiver<Option<String>>>,
+    invocations: u32,
+    last_flush: Instant,
+}
+
+impl Flusher {
+    /// `runtime_done` carries the request id of the latest
+    /// `platform.runtimeDone` seen by the telemetry processor. Without it,
+    /// which is the case when platform events are not subscribed to, a due
+    /// flush happens as soon as the invocation starts and so carries what was
+    /// buffered by the previous ones. An invocation whose `runtimeDone`
+    /// never arrives is flushed just before its deadline.
+    pub fn new(
+        strategy: FlushStrategy,
+        pipelines: Pipelines,
+        runtime_done: Option<watch::Receiver<Option<String>>>,
+    ) -> Self {
+        Flusher {
+            strategy,
+            pipelines,
+            runtime_done,
+            invocations: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Called on INVOKE. If this invocation is due a flush, waits for the
+    /// runtime to finish it and then flushes before the deadline.
+    pub async fn invocation(&mut self, request_id: &str, deadline_ms: u64) {
+        self.invocations += 1;
+        let due = match self.strategy {
+            FlushStrategy::End => true,
+            FlushStrategy::Invocations(n) => self.invocations.is_multiple_of(n),
+            FlushStrategy::Period(period) => self.last_flush.elapsed() >= period,
+        };
+        if !due {
+            return;
+        }
+
+        if let Some(runtime_done) = self.runtime_done.as_mut() {
+            let wait = remaining(deadline_ms).saturating_sub(FLUSH_RESERVE);
+            let done = runtime_done.wait_for(|done| done.as_deref() == Some(request_id));
+            match tokio::time::timeout(wait, done).await {
+                Ok(Ok(_)) => debug!("Runtime done with {}, flushing", request_id),
+                Ok(Err(_)) => warn!("Telemetry processor stopped, flushing without runtimeDone"),
+                Err(_) => warn!(
+                    "No runtimeDone for {} within {:?}, flushing anyway",
+                    request_id, wait
+                ),
+            }
+        }
+        self.flush(deadline_ms).await;
+    }
+
+    /// Force-flushes every pipeline, giving up at `deadline_ms`. Returns
+    /// whether everything was exported in time.
+    pub async fn flush(&mut self, deadline_ms: u64) -> bool {
+        self.last_flush = Instant::now();
+        let budget = remaining(deadline_ms);
+        if budget.is_zero() {
+            warn!("Deadline already passed, skipping flush");
+            return false;
+        }
+        self.pipelines.force_flush(budget).await.is_empty()
+    }
+}
+
+/// Time left until a deadline given in milliseconds since the epoch.
+pub fn remaining(deadline_ms: u64) -> Duration {
+    let deadline = UNIX_EPOCH + Duration::from_millis(deadline_ms);
+    deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deadline_in(duration: Duration) -> u64 {
+        (SystemTime::now() + duration)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_late_runtime_done() {
+        let (runtime_done, receiver) = watch::channel(None);
+        let mut flusher = Flusher::new(FlushStrategy::End, Pipelines::default(), Some(receiver));
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            runtime_done.send_replace(Some("other".to_string()));
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            runtime_done.send_replace(Some("req-1".to_string()));
+            runtime_done
+        });
+
+        let started = Instant::now();
+        flusher
+            .invocation("req-1", deadline_in(Duration::from_secs(30)))
+            .await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(600), "{:?}", waited);
+        assert!(waited < Duration::from_secs(5), "{:?}", waited);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_before_the_deadline() {
+        let (_runtime_done, receiver) = watch::channel(None);
+        let mut flusher = Flusher::new(FlushStrategy::End, Pipelines::default(), Some(receiver));
+
+        let started = Instant::now();
+        flusher
+            .invocation(
+                "req-1",
+                deadline_in(FLUSH_RESERVE + Duration::from_millis(400)),
+            )
+            .await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(300), "{:?}", waited);
+        assert!(
+            waited < FLUSH_RESERVE + Duration::from_millis(400),
+            "{:?}",
+            waited
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_invocations_not_due() {
+        let (_runtime_done, receiver) = watch::channel(None);
+        let mut flusher = Flusher::new(
+            FlushStrategy::Invocations(2),
+            Pipelines::default(),
+            Some(receiver),
+        );
+
+        let started = Instant::now();
+        flusher
+            .invocation("req-1", deadline_in(Duration::from_secs(30)))
+            .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parses_flush_strategies() {
+        assert_eq!("end".parse(), Ok(FlushStrategy::End));
+        assert_eq!(" invocations:5 ".parse(), Ok(FlushStrategy::Invocations(5)));
+        assert_eq!(
+            "period:60".parse(),
+            Ok(FlushStrategy::Period(Duration::from_secs(60)))
+        );
+        for invalid in ["", "invocations:0", "period:0", "period:soon", "always"] {
+            assert!(invalid.parse::<FlushStrategy>().is_err(), "{}", invalid);
+        }
+    }
+}
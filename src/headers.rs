@@ -0,0 +1,220 @@
+//! Exporter headers, whose values may be read from the environment, a file,
+//! Secrets Manager or Parameter Store so API keys stay out of the
+//! configuration, and are redacted whenever the configuration is logged.
+
+use crate::exporter;
+use crate::secrets::{self, SecretRef};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+// Header names containing any of these are assumed to carry credentials.
+// Honeycomb's API key goes in `x-honeycomb-team`, whose name says nothing
+// else about it; a bare "team" would also hide harmless headers.
+const SECRET_MARKERS: [&str; 9] = [
+    "auth",
+    "key",
+    "token",
+    "secret",
+    "password",
+    "credential",
+    "signature",
+    "cookie",
+    "honeycomb-team",
+];
+
+/// Where a header value comes from. A plain value may also be a
+/// [`SecretRef`], such as `ssm:/otel/api-key`.
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum HeaderValue {
+    Value(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl fmt::Debug for HeaderValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderValue::Value(value) => write!(f, "{:?}", value),
+            HeaderValue::Env { env } => write!(f, "env:{}", env),
+            HeaderValue::File { file } => write!(f, "file:{}", file.display()),
+        }
+    }
+}
+
+/// Header names and value sources, resolved once at startup.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Headers {
+    sources: BTreeMap<String, HeaderValue>,
+    #[serde(skip)]
+    resolved: BTreeMap<String, String>,
+    #[serde(skip)]
+    references: BTreeMap<String, SecretRef>,
+}
+
+impl Headers {
+    pub fn insert(&mut self, name: String, value: HeaderValue) {
+        self.sources.insert(name, value);
+    }
+
+    /// Reads every value from its source and checks it can be sent,
+    /// returning the problems found. `context` prefixes each problem.
+    pub async fn resolve(&mut self, context: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.resolved.clear();
+        self.references.clear();
+        for (name, source) in &self.sources {
+            let value = match source {
+                HeaderValue::Value(value) => match SecretRef::parse(value) {
+                    None => Ok(value.clone()),
+                    Some(Ok(reference)) => {
+                        let value = secrets::global().resolve(&reference).await;
+                        self.references.insert(name.clone(), reference);
+                        value
+                    }
+                    Some(Err(e)) => Err(e),
+                },
+                HeaderValue::Env { env } => {
+                    env::var(env).map_err(|_| format!("environment variable {} is not set", env))
+                }
+                HeaderValue::File { file } => fs::read_to_string(file)
+                    .map(|contents| contents.trim().to_string())
+                    .map_err(|e| format!("cannot read {}: {}", file.display(), e)),
+            };
+            match value.and_then(|value| exporter::validate_header(name, &value).map(|_| value)) {
+                Ok(value) => {
+                    self.resolved.insert(name.clone(), value);
+                }
+                Err(e) => problems.push(format!("{}.{}: {}", context, name, e)),
+            }
+        }
+        problems
+    }
+
+    /// The resolved values, empty until [`Headers::resolve`] has run.
+    pub fn resolved(&self) -> &BTreeMap<String, String> {
+        &self.resolved
+    }
+
+    /// The headers whose values came from a [`SecretRef`] and may change
+    /// when the secrets are refreshed.
+    pub fn references(&self) -> &BTreeMap<String, SecretRef> {
+        &self.references
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, source) in &self.sources {
+            match source {
+                HeaderValue::Value(value)
+                    if looks_secret(name) && SecretRef::parse(value).is_none() =>
+                {
+                    map.entry(name, &Redacted)
+                }
+                source => map.entry(name, source),
+            };
+        }
+        map.finish()
+    }
+}
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn headers(entries: &[(&str, HeaderValue)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in entries {
+            headers.insert(name.to_string(), value.clone());
+        }
+        headers
+    }
+
+    fn value(value: &str) -> HeaderValue {
+        HeaderValue::Value(value.to_string())
+    }
+
+    #[test]
+    fn redacts_values_of_headers_that_look_like_credentials() {
+        for name in [
+            "Authorization",
+            "x-api-key",
+            "X-Auth-Token",
+            "client-secret",
+            "x-password",
+            "x-credentials",
+            "x-signature",
+            "cookie",
+            "x-honeycomb-team",
+        ] {
+            let debug = format!("{:?}", headers(&[(name, value("hunter2"))]));
+            assert_eq!(debug, format!("{{{:?}: <redacted>}}", name));
+        }
+    }
+
+    #[test]
+    fn shows_other_headers() {
+        let headers = headers(&[
+            ("x-team", value("payments")),
+            ("x-scope-orgid", value("tenant-1")),
+        ]);
+        assert_eq!(
+            format!("{:?}", headers),
+            r#"{"x-scope-orgid": "tenant-1", "x-team": "payments"}"#
+        );
+    }
+
+    // Values read from elsewhere are only ever shown by their source, even
+    // once resolved, and so are secret references.
+    #[tokio::test]
+    async fn shows_only_where_other_values_come_from() {
+        let path = env::temp_dir().join(format!("microfiber-header-{}", std::process::id()));
+        writeln!(fs::File::create(&path).unwrap(), "file-secret").unwrap();
+        let mut headers = headers(&[
+            (
+                "authorization",
+                HeaderValue::Env {
+                    env: "PATH".to_string(),
+                },
+            ),
+            ("x-api-key", HeaderValue::File { file: path.clone() }),
+        ]);
+        let problems = headers.resolve("exporter.headers").await;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(problems, Vec::<String>::new());
+        assert_eq!(headers.resolved()["x-api-key"], "file-secret");
+        headers.insert("x-token".to_string(), value("ssm:/otel/token"));
+
+        let debug = format!("{:?}", headers);
+        assert_eq!(
+            debug,
+            format!(
+                r#"{{"authorization": env:PATH, "x-api-key": file:{}, "x-token": "ssm:/otel/token"}}"#,
+                path.display()
+            )
+        );
+        assert!(!debug.contains("file-secret"));
+        assert!(!debug.contains(&env::var("PATH").unwrap()));
+    }
+}
@@ -0,0 +1,976 @@
+use crate::semconv;
+use crate::xray::XrayContext;
+use lambda_extension::{
+    InitPhase, InitReportMetrics, InitType, ReportMetrics, RuntimeDoneMetrics,
+    Span as TelemetrySpan, Status, TraceContext,
+};
+use opentelemetry::{
+    global::{BoxedSpan, BoxedTracer},
+    trace::{self, Link, Span, SpanContext, SpanId, SpanKind, TraceContextExt, TraceId, Tracer},
+    Context, KeyValue,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+// Lambda runs one invocation at a time per environment, so anything beyond a
+// handful of open invocations means records were lost and we should stop waiting.
+const MAX_PENDING_INVOCATIONS: usize = 64;
+
+/// An invocation span that has been opened but not yet ended.
+struct Invocation {
+    span: BoxedSpan,
+    start: SystemTime,
+    runtime_done: Option<SystemTime>,
+    child_spans: HashSet<String>,
+    seq: u64,
+    // The X-Ray parent, which the function's own spans are also created under.
+    parent_span_id: SpanId,
+}
+
+/// The invocation or init span that telemetry from the function belongs to.
+#[derive(Clone, Debug)]
+pub struct Correlation {
+    pub request_id: Option<String>,
+    pub span_context: SpanContext,
+    /// The X-Ray parent of the invocation span, which the function's own
+    /// spans are created under too, or invalid.
+    pub parent_span_id: SpanId,
+}
+
+/// An invocation or init span that has ended, kept so telemetry the function
+/// exports afterwards can still be correlated with it.
+struct Ended {
+    correlation: Correlation,
+    start: SystemTime,
+    end: SystemTime,
+}
+
+/// The `status` and `errorType` reported for an init phase or invocation.
+pub struct Outcome<'a> {
+    pub status: &'a Status,
+    pub error_type: Option<&'a str>,
+}
+
+/// The init span of the execution environment, open until `platform.initReport`.
+struct Init {
+    span: BoxedSpan,
+    start: SystemTime,
+    runtime_done: Option<SystemTime>,
+    child_spans: HashSet<String>,
+}
+
+/// Tracks invocations across Telemetry API batches, keyed on request id.
+///
+/// A span is opened at `platform.start`, its end time is taken from
+/// `platform.runtimeDone` and it is ended once `platform.report` arrives.
+/// Invocations that carry an `X-Amzn-Trace-Id` join that X-Ray trace.
+/// The init phase is tracked the same way and the first invocation that
+/// follows it is linked to the init span. The `spans` reported by the
+/// platform become children of the invocation or init span they belong to.
+pub struct InvocationTracker {
+    tracer: BoxedTracer,
+    pending: HashMap<String, Invocation>,
+    next_seq: u64,
+    init: Option<Init>,
+    cold_start: Option<SpanContext>,
+    ended: VecDeque<Ended>,
+    // The resource is fixed before `platform.initStart` arrives, so runtime
+    // metadata from it is carried on every invocation span instead.
+    runtime_attributes: Vec<KeyValue>,
+}
+
+impl InvocationTracker {
+    pub fn new(tracer: BoxedTracer) -> Self {
+        InvocationTracker {
+            tracer,
+            pending: HashMap::new(),
+            next_seq: 0,
+            init: None,
+            cold_start: None,
+            ended: VecDeque::new(),
+            runtime_attributes: Vec::new(),
+        }
+    }
+
+    pub fn init_start(
+        &mut self,
+        time: SystemTime,
+        initialization_type: &InitType,
+        phase: &InitPhase,
+        runtime_version: Option<&str>,
+        runtime_version_arn: Option<&str>,
+    ) {
+        debug!("Opening init span");
+        self.runtime_attributes.clear();
+        if let Some(runtime_version) = runtime_version {
+            self.runtime_attributes.push(KeyValue::new(
+                semconv::AWS_LAMBDA_RUNTIME_VERSION,
+                runtime_version.to_string(),
+            ));
+        }
+        if let Some(runtime_version_arn) = runtime_version_arn {
+            self.runtime_attributes.push(KeyValue::new(
+                semconv::AWS_LAMBDA_RUNTIME_VERSION_ARN,
+                runtime_version_arn.to_string(),
+            ));
+        }
+
+        let runtime_attributes = self.runtime_attributes.clone();
+        let init = self.open_init(time, initialization_type, Some(phase));
+        init.span.set_attributes(runtime_attributes);
+    }
+
+    pub fn init_runtime_done(
+        &mut self,
+        time: SystemTime,
+        initialization_type: &InitType,
+        phase: Option<&InitPhase>,
+        outcome: Outcome<'_>,
+        spans: &[TelemetrySpan],
+    ) {
+        debug!("Init runtime done");
+        if self.init.is_none() {
+            self.open_init(time, initialization_type, phase);
+        }
+        if let Some(init) = self.init.as_mut() {
+            init.runtime_done = Some(time);
+            set_outcome(&mut init.span, &outcome);
+            start_child_spans(
+                &self.tracer,
+                init.span.span_context(),
+                &mut init.child_spans,
+                spans,
+            );
+        }
+    }
+
+    pub fn init_report(
+        &mut self,
+        time: SystemTime,
+        initialization_type: &InitType,
+        phase: &InitPhase,
+        metrics: &InitReportMetrics,
+        spans: &[TelemetrySpan],
+    ) {
+        debug!("Init report");
+        if self.init.is_none() {
+            let start = time - millis(metrics.duration_ms);
+            self.open_init(start, initialization_type, Some(phase));
+        }
+        let Some(mut init) = self.init.take() else {
+            return;
+        };
+        start_child_spans(
+            &self.tracer,
+            init.span.span_context(),
+            &mut init.child_spans,
+            spans,
+        );
+
+        init.span.set_attribute(KeyValue::new(
+            semconv::AWS_LAMBDA_INIT_DURATION_MS,
+            metrics.duration_ms,
+        ));
+        let end = init
+            .runtime_done
+            .unwrap_or_else(|| init.start + millis(metrics.duration_ms));
+        init.span.end_with_timestamp(end);
+        self.remember(init.correlation(), init.start, end);
+    }
+
+    pub fn start(
+        &mut self,
+        request_id: &str,
+        time: SystemTime,
+        version: Option<&str>,
+        tracing: Option<&TraceContext>,
+    ) {
+        debug!("Opening invocation span for {}", request_id);
+        let invocation = self.open(request_id, time, tracing);
+        if let Some(version) = version {
+            invocation
+                .span
+                .set_attribute(KeyValue::new(semconv::FAAS_VERSION, version.to_string()));
+        }
+    }
+
+    pub fn runtime_done(
+        &mut self,
+        request_id: &str,
+        time: SystemTime,
+        metrics: Option<&RuntimeDoneMetrics>,
+        outcome: Outcome<'_>,
+        spans: &[TelemetrySpan],
+        tracing: Option<&TraceContext>,
+    ) {
+        debug!("Runtime done for {}", request_id);
+        let start = metrics.map_or(time, |m| time - millis(m.duration_ms));
+        self.ensure_open(request_id, start, tracing);
+        let Some(invocation) = self.pending.get_mut(request_id) else {
+            return;
+        };
+        invocation.runtime_done = Some(time);
+        set_outcome(&mut invocation.span, &outcome);
+        start_child_spans(
+            &self.tracer,
+            invocation.span.span_context(),
+            &mut invocation.child_spans,
+            spans,
+        );
+        if let Some(metrics) = metrics {
+            invocation
+                .span
+                .set_attributes(semconv::runtime_done_attributes(metrics));
+        }
+    }
+
+    pub fn report(
+        &mut self,
+        request_id: &str,
+        time: SystemTime,
+        metrics: &ReportMetrics,
+        outcome: Outcome<'_>,
+        spans: &[TelemetrySpan],
+        tracing: Option<&TraceContext>,
+    ) {
+        debug!("Report for {}", request_id);
+        let start = time - millis(metrics.duration_ms);
+        self.ensure_open(request_id, start, tracing);
+        let Some(mut invocation) = self.pending.remove(request_id) else {
+            return;
+        };
+        set_outcome(&mut invocation.span, &outcome);
+        start_child_spans(
+            &self.tracer,
+            invocation.span.span_context(),
+            &mut invocation.child_spans,
+            spans,
+        );
+
+        invocation
+            .span
+            .set_attributes(semconv::report_attributes(metrics));
+
+        let end = invocation
+            .runtime_done
+            .unwrap_or_else(|| invocation.start + millis(metrics.duration_ms));
+        invocation.span.end_with_timestamp(end);
+        self.remember(invocation.correlation(request_id), invocation.start, end);
+    }
+
+    /// The invocation (request id and span) or init span that was running at
+    /// `time`, used to correlate log lines with the span they were written in.
+    pub fn span_at(&self, time: SystemTime) -> Option<(Option<&str>, SpanContext)> {
+        let invocation = self
+            .pending
+            .iter()
+            .filter(|(_, invocation)| {
+                invocation.start <= time && invocation.runtime_done.is_none_or(|end| time <= end)
+            })
+            .max_by_key(|(_, invocation)| invocation.seq);
+        if let Some((request_id, invocation)) = invocation {
+            return Some((
+                Some(request_id.as_str()),
+                invocation.span.span_context().clone(),
+            ));
+        }
+        self.init
+            .as_ref()
+            .filter(|init| init.start <= time && init.runtime_done.is_none_or(|end| time <= end))
+            .map(|init| (None, init.span.span_context().clone()))
+    }
+
+    /// The invocation or init span that telemetry from the function belongs
+    /// to: the invocation with its request id, else the span in its trace,
+    /// else the one running at `time`. Recently ended spans are included,
+    /// since the function may export after the platform reports.
+    pub fn invocation_for(
+        &self,
+        request_id: Option<&str>,
+        trace_id: Option<TraceId>,
+        time: SystemTime,
+    ) -> Option<Correlation> {
+        let known = request_id
+            .and_then(|request_id| {
+                self.correlations()
+                    .find(|c| c.request_id.as_deref() == Some(request_id))
+            })
+            .or_else(|| {
+                trace_id.and_then(|trace_id| {
+                    self.correlations()
+                        .find(|c| c.span_context.trace_id() == trace_id)
+                })
+            });
+        if known.is_some() {
+            return known;
+        }
+        let open = self
+            .pending
+            .iter()
+            .filter(|(_, invocation)| {
+                invocation.start <= time && invocation.runtime_done.is_none_or(|end| time <= end)
+            })
+            .max_by_key(|(_, invocation)| invocation.seq)
+            .map(|(request_id, invocation)| invocation.correlation(request_id))
+            .or_else(|| {
+                self.init
+                    .as_ref()
+                    .filter(|init| {
+                        init.start <= time && init.runtime_done.is_none_or(|end| time <= end)
+                    })
+                    .map(Init::correlation)
+            });
+        open.or_else(|| {
+            self.ended
+                .iter()
+                .rev()
+                .find(|ended| ended.start <= time && time <= ended.end)
+                .map(|ended| ended.correlation.clone())
+        })
+    }
+
+    /// Ends every open span, e.g. when the environment shuts down, and
+    /// returns how many there were.
+    pub fn end_all(&mut self) -> usize {
+        let mut ended = self.pending.len();
+        if let Some(mut init) = self.init.take() {
+            ended += 1;
+            warn!("Ending init span without a report");
+            let end = init.runtime_done.unwrap_or_else(SystemTime::now);
+            init.span.end_with_timestamp(end);
+            self.remember(init.correlation(), init.start, end);
+        }
+        let pending: Vec<_> = self.pending.drain().collect();
+        for (request_id, mut invocation) in pending {
+            warn!("Ending invocation {} without a report", request_id);
+            let end = invocation.runtime_done.unwrap_or_else(SystemTime::now);
+            invocation.span.end_with_timestamp(end);
+            self.remember(invocation.correlation(&request_id), invocation.start, end);
+        }
+        ended
+    }
+
+    // Open spans first, then ended ones from the most recent.
+    fn correlations(&self) -> impl Iterator<Item = Correlation> + '_ {
+        let open = self
+            .pending
+            .iter()
+            .map(|(request_id, invocation)| invocation.correlation(request_id));
+        let init = self.init.iter().map(Init::correlation);
+        let ended = self
+            .ended
+            .iter()
+            .rev()
+            .map(|ended| ended.correlation.clone());
+        open.chain(init).chain(ended)
+    }
+
+    fn remember(&mut self, correlation: Correlation, start: SystemTime, end: SystemTime) {
+        if self.ended.len() >= MAX_PENDING_INVOCATIONS {
+            self.ended.pop_front();
+        }
+        self.ended.push_back(Ended {
+            correlation,
+            start,
+            end,
+        });
+    }
+
+    fn ensure_open(&mut self, request_id: &str, start: SystemTime, tracing: Option<&TraceContext>) {
+        if !self.pending.contains_key(request_id) {
+            debug!(
+                "No platform.start seen for {}, opening span late",
+                request_id
+            );
+            self.open(request_id, start, tracing);
+        }
+    }
+
+    fn open(
+        &mut self,
+        request_id: &str,
+        start: SystemTime,
+        tracing: Option<&TraceContext>,
+    ) -> &mut Invocation {
+        if self.pending.len() >= MAX_PENDING_INVOCATIONS {
+            self.evict_oldest();
+        }
+
+        let mut builder = self
+            .tracer
+            .span_builder("invocation")
+            .with_kind(SpanKind::Server)
+            .with_start_time(start);
+        let cold_start = self.cold_start.take();
+        let mut attributes = semconv::invocation_attributes(request_id, cold_start.is_some());
+        attributes.extend(self.runtime_attributes.iter().cloned());
+        if let Some(init) = cold_start {
+            builder = builder.with_links(vec![Link::with_context(init)]);
+        }
+
+        let mut cx = Context::new();
+        let mut parent_span_id = SpanId::INVALID;
+        if let Some(xray) = tracing.and_then(XrayContext::from_trace_context) {
+            debug!("Invocation {} joins trace {}", request_id, xray.trace_id);
+            builder = builder.with_trace_id(xray.trace_id);
+            if let Some(span_id) = xray.span_id {
+                builder = builder.with_span_id(span_id);
+            }
+            if let Some(parent) = xray.parent_span_context() {
+                parent_span_id = parent.span_id();
+                cx = cx.with_remote_span_context(parent);
+            }
+        }
+        let span = builder
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &cx);
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(mut previous) = self.pending.insert(
+            request_id.to_string(),
+            Invocation {
+                span,
+                start,
+                runtime_done: None,
+                child_spans: HashSet::new(),
+                seq,
+                parent_span_id,
+            },
+        ) {
+            warn!("Duplicate platform.start for {}", request_id);
+            previous.span.end();
+        }
+        self.pending.get_mut(request_id).unwrap()
+    }
+
+    fn open_init(
+        &mut self,
+        start: SystemTime,
+        initialization_type: &InitType,
+        phase: Option<&InitPhase>,
+    ) -> &mut Init {
+        let span = self
+            .tracer
+            .span_builder("init")
+            .with_kind(SpanKind::Internal)
+            .with_start_time(start)
+            .with_attributes(semconv::init_attributes(initialization_type, phase))
+            .start(&self.tracer);
+
+        self.cold_start = Some(span.span_context().clone());
+        if let Some(mut previous) = self.init.replace(Init {
+            span,
+            start,
+            runtime_done: None,
+            child_spans: HashSet::new(),
+        }) {
+            warn!("Init started again before the previous init was reported");
+            previous.span.end();
+        }
+        self.init.as_mut().unwrap()
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, invocation)| invocation.seq)
+            .map(|(request_id, _)| request_id.clone());
+        if let Some(request_id) = oldest {
+            warn!("Too many open invocations, ending {}", request_id);
+            if let Some(mut invocation) = self.pending.remove(&request_id) {
+                invocation.span.end();
+            }
+        }
+    }
+}
+
+impl Invocation {
+    fn correlation(&self, request_id: &str) -> Correlation {
+        Correlation {
+            request_id: Some(request_id.to_string()),
+            span_context: self.span.span_context().clone(),
+            parent_span_id: self.parent_span_id,
+        }
+    }
+}
+
+impl Init {
+    fn correlation(&self) -> Correlation {
+        Correlation {
+            request_id: None,
+            span_context: self.span.span_context().clone(),
+            parent_span_id: SpanId::INVALID,
+        }
+    }
+}
+
+fn millis(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+}
+
+/// Starts and ends a child span for each platform span not already emitted
+/// under `parent`, using the exact start and duration Lambda reported.
+fn start_child_spans(
+    tracer: &BoxedTracer,
+    parent: &SpanContext,
+    emitted: &mut HashSet<String>,
+    spans: &[TelemetrySpan],
+) {
+    let cx = Context::new().with_remote_span_context(parent.clone());
+    for span in spans {
+        if !emitted.insert(span.name.clone()) {
+            continue;
+        }
+        let start = SystemTime::from(span.start);
+        let mut child = tracer
+            .span_builder(span.name.clone())
+            .with_kind(SpanKind::Internal)
+            .with_start_time(start)
+            .with_attributes(vec![KeyValue::new(
+                semconv::AWS_LAMBDA_SPAN_DURATION_MS,
+                span.duration_ms,
+            )])
+            .start_with_context(tracer, &cx);
+        child.end_with_timestamp(start + millis(span.duration_ms));
+    }
+}
+
+/// Marks the span as failed unless the platform reported success.
+///
+/// `error.type` carries Lambda's `errorType` (e.g. `Runtime.ExitError`,
+/// `Runtime.OutOfMemory`, `Sandbox.Timedout`) and falls back to the status
+/// itself, so timeouts stay distinguishable from runtime crashes.
+fn set_outcome(span: &mut BoxedSpan, outcome: &Outcome<'_>) {
+    let status = semconv::status_name(outcome.status);
+    span.set_attribute(KeyValue::new(semconv::AWS_LAMBDA_STATUS, status));
+    if *outcome.status == Status::Success {
+        return;
+    }
+
+    let error_type = outcome.error_type.unwrap_or(status).to_string();
+    span.set_attribute(KeyValue::new(semconv::ERROR_TYPE, error_type.clone()));
+    span.set_status(trace::Status::error(format!("{}: {}", status, error_type)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_extension::TracingType;
+    use opentelemetry::trace::{Status as SpanStatus, TracerProvider as _};
+    use opentelemetry::Value;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    struct Collected(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collected {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl Collected {
+        fn named(&self, name: &str) -> Vec<SpanData> {
+            let spans = self.0.lock().unwrap();
+            spans
+                .iter()
+                .filter(|span| span.name == name)
+                .cloned()
+                .collect()
+        }
+
+        fn only(&self, name: &str) -> SpanData {
+            let mut spans = self.named(name);
+            assert_eq!(spans.len(), 1, "{} {} spans", spans.len(), name);
+            spans.remove(0)
+        }
+    }
+
+    fn tracker() -> (InvocationTracker, Collected, TracerProvider) {
+        let collected = Collected::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(collected.clone())
+            .build();
+        let tracer = BoxedTracer::new(Box::new(provider.tracer("test")));
+        (InvocationTracker::new(tracer), collected, provider)
+    }
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis(ms)
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    }
+
+    fn success() -> Outcome<'static> {
+        Outcome {
+            status: &Status::Success,
+            error_type: None,
+        }
+    }
+
+    fn runtime_done(duration_ms: f64) -> RuntimeDoneMetrics {
+        RuntimeDoneMetrics {
+            duration_ms,
+            produced_bytes: Some(42),
+        }
+    }
+
+    fn report(duration_ms: f64) -> ReportMetrics {
+        ReportMetrics {
+            duration_ms,
+            billed_duration_ms: duration_ms as u64 + 1,
+            memory_size_mb: 128,
+            max_memory_used_mb: 64,
+            init_duration_ms: None,
+            restore_duration_ms: None,
+        }
+    }
+
+    fn platform_span(name: &str, start: SystemTime, duration_ms: f64) -> TelemetrySpan {
+        TelemetrySpan {
+            duration_ms,
+            name: name.to_string(),
+            start: start.into(),
+        }
+    }
+
+    fn xray(value: &str) -> TraceContext {
+        TraceContext {
+            span_id: None,
+            r#type: TracingType::AmznTraceId,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn spans_an_invocation_from_start_to_runtime_done() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.start("req-1", at(0), Some("$LATEST"), None);
+        assert!(collected.named("invocation").is_empty());
+
+        tracker.runtime_done(
+            "req-1",
+            at(120),
+            Some(&runtime_done(120.0)),
+            success(),
+            &[],
+            None,
+        );
+        assert!(collected.named("invocation").is_empty());
+
+        tracker.report("req-1", at(150), &report(120.0), success(), &[], None);
+        let span = collected.only("invocation");
+        assert_eq!(span.span_kind, SpanKind::Server);
+        assert_eq!(span.start_time, at(0));
+        assert_eq!(span.end_time, at(120));
+        assert_eq!(
+            attribute(&span, semconv::FAAS_INVOCATION_ID),
+            Some("req-1".into())
+        );
+        assert_eq!(
+            attribute(&span, semconv::FAAS_VERSION),
+            Some("$LATEST".into())
+        );
+        assert_eq!(
+            attribute(&span, semconv::AWS_LAMBDA_PRODUCED_BYTES),
+            Some(42.into())
+        );
+        assert_eq!(
+            attribute(&span, semconv::AWS_LAMBDA_BILLED_DURATION_MS),
+            Some(121.into())
+        );
+        assert_eq!(span.status, SpanStatus::Unset);
+    }
+
+    #[test]
+    fn opens_the_span_late_without_platform_start() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.runtime_done(
+            "req-1",
+            at(1_000),
+            Some(&runtime_done(250.0)),
+            success(),
+            &[],
+            None,
+        );
+        tracker.report("req-1", at(1_050), &report(250.0), success(), &[], None);
+        let span = collected.only("invocation");
+        assert_eq!(span.start_time, at(750));
+        assert_eq!(span.end_time, at(1_000));
+
+        tracker.report("req-2", at(3_000), &report(500.0), success(), &[], None);
+        let late = collected.named("invocation").pop().unwrap();
+        assert_eq!(late.start_time, at(2_500));
+        assert_eq!(late.end_time, at(3_000));
+    }
+
+    #[test]
+    fn correlates_records_of_interleaved_invocations() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.start("req-1", at(0), None, None);
+        tracker.start("req-2", at(10), None, None);
+        tracker.report("req-2", at(60), &report(50.0), success(), &[], None);
+        tracker.report("req-1", at(90), &report(90.0), success(), &[], None);
+
+        let spans = collected.named("invocation");
+        let ids: Vec<_> = spans
+            .iter()
+            .map(|span| attribute(span, semconv::FAAS_INVOCATION_ID).unwrap())
+            .collect();
+        assert_eq!(ids, [Value::from("req-2"), Value::from("req-1")]);
+        assert_eq!((spans[0].start_time, spans[0].end_time), (at(10), at(60)));
+        assert_eq!((spans[1].start_time, spans[1].end_time), (at(0), at(90)));
+    }
+
+    #[test]
+    fn ends_the_oldest_invocation_beyond_the_limit() {
+        let (mut tracker, collected, _provider) = tracker();
+        for i in 0..=MAX_PENDING_INVOCATIONS {
+            tracker.start(&format!("req-{}", i), at(i as u64), None, None);
+        }
+        let evicted = collected.only("invocation");
+        assert_eq!(
+            attribute(&evicted, semconv::FAAS_INVOCATION_ID),
+            Some("req-0".into())
+        );
+        assert_eq!(tracker.pending.len(), MAX_PENDING_INVOCATIONS);
+
+        assert_eq!(tracker.end_all(), MAX_PENDING_INVOCATIONS);
+        assert_eq!(
+            collected.named("invocation").len(),
+            MAX_PENDING_INVOCATIONS + 1
+        );
+    }
+
+    #[test]
+    fn links_the_first_invocation_to_the_init_span() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.init_start(
+            at(0),
+            &InitType::OnDemand,
+            &InitPhase::Init,
+            Some("python:3.12.v1"),
+            None,
+        );
+        tracker.init_runtime_done(at(300), &InitType::OnDemand, None, success(), &[]);
+        tracker.init_report(
+            at(310),
+            &InitType::OnDemand,
+            &InitPhase::Init,
+            &InitReportMetrics { duration_ms: 300.0 },
+            &[],
+        );
+        let init = collected.only("init");
+        assert_eq!((init.start_time, init.end_time), (at(0), at(300)));
+        assert_eq!(
+            attribute(&init, semconv::AWS_LAMBDA_INITIALIZATION_TYPE),
+            Some("on-demand".into())
+        );
+        assert_eq!(
+            attribute(&init, semconv::AWS_LAMBDA_INIT_PHASE),
+            Some("init".into())
+        );
+
+        tracker.start("req-1", at(400), None, None);
+        tracker.report("req-1", at(500), &report(100.0), success(), &[], None);
+        tracker.start("req-2", at(600), None, None);
+        tracker.report("req-2", at(700), &report(100.0), success(), &[], None);
+
+        let invocations = collected.named("invocation");
+        let (first, second) = (&invocations[0], &invocations[1]);
+        assert_eq!(first.links.links.len(), 1);
+        assert_eq!(first.links.links[0].span_context, init.span_context);
+        assert_eq!(attribute(first, semconv::FAAS_COLDSTART), Some(true.into()));
+        assert_eq!(
+            attribute(first, semconv::AWS_LAMBDA_RUNTIME_VERSION),
+            Some("python:3.12.v1".into())
+        );
+        assert!(second.links.links.is_empty());
+        assert_eq!(
+            attribute(second, semconv::FAAS_COLDSTART),
+            Some(false.into())
+        );
+    }
+
+    #[test]
+    fn spans_an_init_reported_without_its_start() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.init_report(
+            at(1_000),
+            &InitType::SnapStart,
+            &InitPhase::Init,
+            &InitReportMetrics { duration_ms: 400.0 },
+            &[],
+        );
+        let init = collected.only("init");
+        assert_eq!((init.start_time, init.end_time), (at(600), at(1_000)));
+        assert_eq!(
+            attribute(&init, semconv::AWS_LAMBDA_INIT_DURATION_MS),
+            Some(400.0.into())
+        );
+    }
+
+    #[test]
+    fn emits_each_platform_span_once_under_its_invocation() {
+        let (mut tracker, collected, _provider) = tracker();
+        tracker.start("req-1", at(0), None, None);
+        let latency = platform_span("responseLatency", at(5), 20.0);
+        tracker.runtime_done(
+            "req-1",
+            at(100),
+            Some(&runtime_done(100.0)),
+            success(),
+            std::slice::from_ref(&latency),
+            None,
+        );
+        let overhead = platform_span("runtimeOverhead", at(100), 3.5);
+        tracker.report(
+            "req-1",
+            at(110),
+            &report(100.0),
+            success(),
+            &[latency, overhead],
+            None,
+        );
+
+        let invocation = collected.only("invocation");
+        let latency = collected.only("responseLatency");
+        let overhead = collected.only("runtimeOverhead");
+        for child in [&latency, &overhead] {
+            assert_eq!(
+                child.span_context.trace_id(),
+                invocation.span_context.trace_id()
+            );
+            assert_eq!(child.parent_span_id, invocation.span_context.span_id());
+        }
+        assert_eq!((latency.start_time, latency.end_time), (at(5), at(25)));
+        assert_eq!(overhead.end_time, at(100) + Duration::from_micros(3_500));
+        assert_eq!(
+            attribute(&latency, semconv::AWS_LAMBDA_SPAN_DURATION_MS),
+            Some(20.0.into())
+        );
+    }
+
+    #[test]
+    fn marks_failed_invocations_with_their_error_type() {
+        let (mut tracker, collected, _provider) = tracker();
+        let outcomes = [
+            (Status::Error, Some("Runtime.ExitError")),
+            (Status::Failure, Some("Runtime.OutOfMemory")),
+            (Status::Timeout, None),
+        ];
+        for (i, (status, error_type)) in outcomes.iter().enumerate() {
+            let request_id = format!("req-{}", i);
+            let outcome = Outcome {
+                status,
+                error_type: *error_type,
+            };
+            tracker.report(&request_id, at(100), &report(100.0), outcome, &[], None);
+        }
+
+        let spans = collected.named("invocation");
+        let failed: Vec<_> = spans
+            .iter()
+            .map(|span| {
+                (
+                    attribute(span, semconv::AWS_LAMBDA_STATUS).unwrap(),
+                    attribute(span, semconv::ERROR_TYPE).unwrap(),
+                    span.status.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                (
+                    "error".into(),
+                    "Runtime.ExitError".into(),
+                    SpanStatus::error("error: Runtime.ExitError")
+                ),
+                (
+                    "failure".into(),
+                    "Runtime.OutOfMemory".into(),
+                    SpanStatus::error("failure: Runtime.OutOfMemory")
+                ),
+                (
+                    "timeout".into(),
+                    "timeout".into(),
+                    SpanStatus::error("timeout: timeout")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_the_x_ray_trace_of_the_invocation() {
+        let (mut tracker, collected, _provider) = tracker();
+        let tracing =
+            xray("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1");
+        tracker.start("req-1", at(0), None, Some(&tracing));
+        tracker.report(
+            "req-1",
+            at(100),
+            &report(100.0),
+            success(),
+            &[],
+            Some(&tracing),
+        );
+        tracker.start("req-2", at(200), None, None);
+        tracker.report("req-2", at(300), &report(100.0), success(), &[], None);
+
+        let spans = collected.named("invocation");
+        assert_eq!(
+            spans[0].span_context.trace_id(),
+            TraceId::from_hex("5759e988bd862e3fe1be46a994272793").unwrap()
+        );
+        assert_eq!(
+            spans[0].parent_span_id,
+            SpanId::from_hex("53995c3f42cd8ad8").unwrap()
+        );
+        assert_ne!(
+            spans[1].span_context.trace_id(),
+            spans[0].span_context.trace_id()
+        );
+        assert_eq!(spans[1].parent_span_id, SpanId::INVALID);
+    }
+
+    #[test]
+    fn finds_the_invocation_for_function_telemetry() {
+        let (mut tracker, _collected, _provider) = tracker();
+        tracker.start("req-1", at(0), None, None);
+        tracker.runtime_done("req-1", at(100), None, success(), &[], None);
+        tracker.report("req-1", at(110), &report(100.0), success(), &[], None);
+        tracker.start("req-2", at(200), None, None);
+
+        let by_id = tracker
+            .invocation_for(Some("req-1"), None, at(250))
+            .unwrap();
+        assert_eq!(by_id.request_id.as_deref(), Some("req-1"));
+        let trace_id = by_id.span_context.trace_id();
+        let by_trace = tracker
+            .invocation_for(None, Some(trace_id), at(250))
+            .unwrap();
+        assert_eq!(by_trace.request_id.as_deref(), Some("req-1"));
+        let by_time = tracker.invocation_for(None, None, at(250)).unwrap();
+        assert_eq!(by_time.request_id.as_deref(), Some("req-2"));
+        let ended = tracker.invocation_for(None, None, at(50)).unwrap();
+        assert_eq!(ended.request_id.as_deref(), Some("req-1"));
+        assert!(tracker.invocation_for(None, None, at(150)).is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::parsing::{
+    AutoParser, JavaParser, JsonParser, LambdaParser, LogParser, LogfmtParser, PythonParser,
+};
+use crate::semconv;
+use opentelemetry::{
+    logs::{AnyValue, LogRecord as _, Logger as _, Severity},
+    trace::SpanContext,
+};
+use opentelemetry_sdk::logs::{Logger, TraceContext};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Where a log line was written, as reported by the Telemetry API.
+#[derive(Clone, Copy, Debug)]
+pub enum LogSource {
+    Function,
+    Extension,
+    Platform,
+}
+
+impl LogSource {
+    fn name(self) -> &'static str {
+        match self {
+            LogSource::Function => "function",
+            LogSource::Extension => "extension",
+            LogSource::Platform => "platform",
+        }
+    }
+}
+
+/// How structure is extracted from log lines.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogParsing {
+    /// Each line in whichever of the formats below it is in.
+    #[default]
+    Auto,
+    /// A JSON object per line.
+    Json,
+    /// The managed runtimes' tab-separated prefix.
+    Lambda,
+    /// `key=value` pairs.
+    Logfmt,
+    /// Python's `logging` defaults.
+    Python,
+    /// The default Logback and Log4j layouts.
+    Java,
+    /// Lines are exported as plain bodies.
+    Off,
+}
+
+impl LogParsing {
+    fn parser(self) -> Option<Box<dyn LogParser>> {
+        match self {
+            LogParsing::Auto => Some(Box::new(AutoParser)),
+            LogParsing::Json => Some(Box::new(JsonParser)),
+            LogParsing::Lambda => Some(Box::new(LambdaParser)),
+            LogParsing::Logfmt => Some(Box::new(LogfmtParser)),
+            LogParsing::Python => Some(Box::new(PythonParser)),
+            LogParsing::Java => Some(Box::new(JavaParser)),
+            LogParsing::Off => None,
+        }
+    }
+}
+
+/// A severity threshold, named as in most logging libraries.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl LogLevel {
+    pub fn severity(self) -> Severity {
+        match self {
+            LogLevel::Trace => Severity::Trace,
+            LogLevel::Debug => Severity::Debug,
+            LogLevel::Info => Severity::Info,
+            LogLevel::Warn => Severity::Warn,
+            LogLevel::Error => Severity::Error,
+            LogLevel::Fatal => Severity::Fatal,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub parsing: LogParsing,
+    /// Lines found to be less severe are dropped, and so are records the
+    /// function sends to the receiver. Those whose severity is unknown are
+    /// always kept.
+    pub min_level: Option<LogLevel>,
+}
+
+/// Turns Telemetry API log lines into OTLP log records.
+pub struct LogEmitter {
+    logger: Logger,
+    parser: Option<Box<dyn LogParser>>,
+    min_severity: Option<Severity>,
+}
+
+impl LogEmitter {
+    pub fn new(logger: Logger, settings: LogSettings) -> Self {
+        LogEmitter {
+            logger,
+            parser: settings.parsing.parser(),
+            min_severity: settings.min_level.map(LogLevel::severity),
+        }
+    }
+
+    /// Emits `line` with its original timestamp, correlated with the span
+    /// and invocation it was written during, if any, unless it is below the
+    /// minimum level.
+    pub fn emit(
+        &self,
+        source: LogSource,
+        time: SystemTime,
+        line: &str,
+        span: Option<(Option<&str>, SpanContext)>,
+    ) {
+        let parsed = self
+            .parser
+            .as_ref()
+            .and_then(|parser| parser.parse(line))
+            .unwrap_or_default();
+        if let (Some(severity), Some(min_severity)) = (parsed.severity, self.min_severity) {
+            if severity < min_severity {
+                return;
+            }
+        }
+
+        let mut record = self.logger.create_log_record();
+        record.set_timestamp(time);
+        record.set_observed_timestamp(SystemTime::now());
+        record.set_body(AnyValue::from(line.to_string()));
+        record.add_attribute(semconv::AWS_LAMBDA_LOG_TYPE, source.name());
+        if let Some(body) = parsed.body {
+            record.set_body(body);
+        }
+        if let Some(timestamp) = parsed.timestamp {
+            record.set_timestamp(timestamp);
+        }
+        if let Some(severity) = parsed.severity {
+            record.set_severity_number(severity);
+            record.set_severity_text(severity.name());
+        }
+        record.add_attributes(parsed.attributes);
+
+        let (request_id, span_context) = match span {
+            Some((request_id, span_context)) => (request_id, Some(span_context)),
+            None => (None, None),
+        };
+        if let Some(request_id) = request_id.map(str::to_string).or(parsed.request_id) {
+            record.add_attribute(semconv::FAAS_INVOCATION_ID, request_id);
+        }
+        if let Some(span_context) = span_context {
+            record.trace_context = Some(TraceContext::from(&span_context));
+        }
+
+        self.logger.emit(record);
+    }
+
+    /// Emits the last record before the environment shuts down, saying why
+    /// and how many spans had to be ended without a report.
+    pub fn emit_shutdown(&self, reason: &str, unfinished_spans: usize) {
+        let mut record = self.logger.create_log_record();
+        let now = SystemTime::now();
+        record.set_timestamp(now);
+        record.set_observed_timestamp(now);
+        if reason == "spindown" {
+            record.set_severity_number(Severity::Info);
+            record.set_severity_text("INFO");
+        } else {
+            record.set_severity_number(Severity::Warn);
+            record.set_severity_text("WARN");
+        }
+        record.set_body(AnyValue::from(format!(
+            "Extension shutting down: {}",
+            reason
+        )));
+        record.add_attribute(semconv::AWS_LAMBDA_LOG_TYPE, LogSource::Extension.name());
+        record.add_attribute(semconv::AWS_LAMBDA_SHUTDOWN_REASON, reason.to_string());
+        record.add_attribute(
+            semconv::AWS_LAMBDA_UNFINISHED_SPANS,
+            unfinished_spans as i64,
+        );
+        self.logger.emit(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use opentelemetry::logs::{LogResult, LoggerProvider as _};
+    use opentelemetry_sdk::export::logs::{LogBatch, LogExporter};
+    use opentelemetry_sdk::logs::{LogRecord, LoggerProvider};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    struct Collected(Arc<Mutex<Vec<LogRecord>>>);
+
+    #[async_trait]
+    impl LogExporter for Collected {
+        async fn export(&mut self, batch: LogBatch<'_>) -> LogResult<()> {
+            let mut records = self.0.lock().unwrap();
+            records.extend(batch.iter().map(|(record, _)| record.clone()));
+            Ok(())
+        }
+    }
+
+    // Emits each line and returns the severity of the records exported.
+    fn emit(
+        settings: LogSettings,
+        lines: &[&str],
+    ) -> Vec<(Option<Severity>, Option<&'static str>)> {
+        let collected = Collected::default();
+        let provider = LoggerProvider::builder()
+            .with_simple_exporter(collected.clone())
+            .build();
+        let emitter = LogEmitter::new(provider.logger("test"), settings);
+        for line in lines {
+            emitter.emit(LogSource::Function, SystemTime::now(), line, None);
+        }
+        let records = collected.0.lock().unwrap();
+        records
+            .iter()
+            .map(|record| (record.severity_number, record.severity_text))
+            .collect()
+    }
+
+    #[test]
+    fn sets_the_severity_and_its_name() {
+        let emitted = emit(LogSettings::default(), &["[ERROR] a", "NOTICE: b"]);
+        assert_eq!(
+            emitted,
+            vec![
+                (Some(Severity::Error), Some("ERROR")),
+                (Some(Severity::Info2), Some("INFO2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_lines_below_the_minimum_level() {
+        let settings = LogSettings {
+            min_level: Some(LogLevel::Warn),
+            ..Default::default()
+        };
+        let emitted = emit(
+            settings,
+            &[
+                r#"{"level":"debug","msg":"a"}"#,
+                "INFO: b",
+                r#"{"level":30,"msg":"c"}"#,
+                "WARN: d",
+                r#"{"level":"ERROR","message":"e"}"#,
+                "[CRITICAL] f",
+            ],
+        );
+        assert_eq!(
+            emitted,
+            vec![
+                (Some(Severity::Warn), Some("WARN")),
+                (Some(Severity::Error), Some("ERROR")),
+                (Some(Severity::Fatal), Some("FATAL")),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_lines_of_unknown_severity() {
+        let settings = LogSettings {
+            min_level: Some(LogLevel::Fatal),
+            ..Default::default()
+        };
+        let emitted = emit(
+            settings,
+            &["plain text", r#"{"level":"loud","msg":"a"}"#, "ERROR: b"],
+        );
+        assert_eq!(emitted, vec![(None, None), (None, None)]);
+    }
+
+    #[test]
+    fn keeps_everything_without_parsing() {
+        let settings = LogSettings {
+            parsing: LogParsing::Off,
+            min_level: Some(LogLevel::Error),
+        };
+        let emitted = emit(settings, &["DEBUG: a", "[INFO] b"]);
+        assert_eq!(emitted, vec![(None, None), (None, None)]);
+    }
+}
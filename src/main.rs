@@ -1,220 +1,429 @@
 use lambda_extension::{
-    service_fn, Error, Extension, LambdaTelemetry, LambdaTelemetryRecord, SharedService,
+    service_fn, Error, Extension, LambdaEvent, LambdaTelemetry, LambdaTelemetryRecord, NextEvent,
+    SharedService,
 };
 use opentelemetry::{
     global,
-    trace::{Span, TraceContextExt, TraceError, Tracer},
-    KeyValue,
+    logs::{LogError, LoggerProvider as _},
+    metrics::MetricsError,
+    trace::TraceError,
 };
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{runtime, trace as sdktrace};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::env;
-use tracing::{debug, error, info, warn};
-use tracing_subscriber;
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct Config {
-    collector_endpoint: String,
-    service_name: String,
-}
+use opentelemetry_otlp::{LogExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder};
+use opentelemetry_sdk::export::trace::SpanExporter as _;
+use opentelemetry_sdk::logs::{BatchLogProcessor, LoggerProvider};
+use opentelemetry_sdk::metrics::reader::{DefaultAggregationSelector, DefaultTemporalitySelector};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
 
-fn load_config() -> Config {
-    let config = Config {
-        collector_endpoint: env::var("COLLECTOR_ENDPOINT")
-            .unwrap_or_else(|_| "http://localhost:4317".to_string()),
-        service_name: env::var("SERVICE_NAME").unwrap_or_else(|_| "lambda_extension".to_string()),
-    };
-    debug!("Loaded configuration: {:?}", config);
-    config
-}
+mod config;
+mod exporter;
+mod flush;
+mod headers;
+mod invocation;
+mod logs;
+mod metrics;
+mod otlp;
+mod parsing;
+mod pipeline;
+mod receiver;
+mod resource;
+mod retry;
+mod sampling;
+mod secrets;
+mod semconv;
+mod shutdown;
+mod spill;
+mod stitch;
+mod subscription;
+mod xray;
+
+use config::{Config, Vars};
+use exporter::ExporterBuilder;
+use flush::{Flusher, Signal};
+use invocation::{InvocationTracker, Outcome};
+use logs::{LogEmitter, LogSource};
+use metrics::InvocationMetrics;
+use pipeline::{Pipeline, Pipelines, Shared};
+use receiver::{ForwardedMetrics, ForwardingMetricsExporter, Receiver};
+use resource::{AdoptingLogExporter, AdoptingSpanExporter};
+use retry::{RetryingLogExporter, RetryingMetricsExporter, RetryingSpanExporter};
+use shutdown::ShutdownCoordinator;
+use spill::Spool;
+use subscription::TelemetryType;
 
-#[tokio::main]
-async fn main() {
+fn main() {
     tracing_subscriber::fmt::init();
 
     info!("Lambda Extension starting up");
 
-    let config = load_config();
+    // Taken before the runtime starts its threads, since it changes the
+    // environment.
+    let vars = Vars::take();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the Tokio runtime")
+        .block_on(run(vars));
+}
+
+async fn run(vars: Vars) {
+    let config = match config::load(&vars).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     info!("Loaded configuration: {:?}", config);
 
-    let tracer_provider = init_opentelemetry(&config).expect("failed to initialize opentelemetry");
+    // Export failures in the batch processors are only reported here.
+    if let Err(e) = global::set_error_handler(|e| {
+        error!("OpenTelemetry error: {}", e);
+        secrets::on_export_error(&e);
+    }) {
+        error!("Failed to install the OpenTelemetry error handler: {}", e);
+    }
+
+    let resource = resource::detect(config.service_name(), &config.resource_attributes);
+    let mut pipelines = Vec::new();
+    let tracer_provider = init_opentelemetry(&config, resource.clone(), &mut pipelines)
+        .expect("failed to initialize opentelemetry");
     global::set_tracer_provider(tracer_provider);
+    let meter_provider = init_metrics(&config, resource.clone(), &mut pipelines)
+        .expect("failed to initialize metrics");
+    global::set_meter_provider(meter_provider);
+    let logger_provider =
+        init_logs(&config, resource, &mut pipelines).expect("failed to initialize logs");
+    let pipelines = Pipelines(pipelines);
+    // Runs alongside the first invocation rather than holding up INIT.
+    tokio::spawn({
+        let pipelines = pipelines.clone();
+        async move { pipelines.replay().await }
+    });
 
-    let telemetry_processor = SharedService::new(service_fn(handler));
+    let tracker = Arc::new(Mutex::new(InvocationTracker::new(global::tracer(
+        "lambda_extension",
+    ))));
+    let metrics = Arc::new(InvocationMetrics::new(global::meter("lambda_extension")));
+    let log_emitter = Arc::new(LogEmitter::new(
+        logger_provider.logger("lambda_extension"),
+        config.logs.clone(),
+    ));
+    let coordinator = Arc::new(ShutdownCoordinator::new(
+        tracker.clone(),
+        log_emitter.clone(),
+        pipelines.clone(),
+    ));
+    let receiver = Receiver::new(config.receiver, pipelines.clone(), tracker.clone())
+        .with_service_name_adopted(!config.service_name_configured())
+        .with_min_level(config.logs.min_level);
+    if let Err(e) = receiver.start().await {
+        error!("Failed to start the OTLP receiver: {}", e);
+    }
+    let (runtime_done, runtime_done_rx) = watch::channel(None);
+    let telemetry_processor = SharedService::new(service_fn(move |events| {
+        handler(
+            tracker.clone(),
+            metrics.clone(),
+            log_emitter.clone(),
+            runtime_done.clone(),
+            events,
+        )
+    }));
+
+    let flusher = Arc::new(tokio::sync::Mutex::new(Flusher::new(
+        config.flush,
+        pipelines,
+        config
+            .telemetry
+            .includes(TelemetryType::Platform)
+            .then_some(runtime_done_rx),
+    )));
+    let events_coordinator = coordinator.clone();
+    let events_processor =
+        service_fn(move |event| events_handler(flusher.clone(), events_coordinator.clone(), event));
 
     info!("Starting Lambda Extension");
+    let telemetry_types = config.telemetry.type_names();
     let extension_result = Extension::new()
+        .with_events_processor(events_processor)
         .with_telemetry_processor(telemetry_processor)
+        .with_telemetry_types(&telemetry_types)
+        .with_telemetry_buffering(config.telemetry.buffering.into())
         .run()
         .await;
-
-    info!("Lambda Extension shutting down");
-    global::shutdown_tracer_provider();
+    // After SHUTDOWN the run loop blocks until Lambda kills the process, so
+    // returning here means the extension failed.
+    if let Err(e) = extension_result {
+        error!("Lambda Extension exited with error: {:?}", e);
+    }
+    coordinator
+        .shutdown("failure", shutdown::DEFAULT_BUDGET)
+        .await;
 }
 
-fn init_opentelemetry(config: &Config) -> Result<sdktrace::TracerProvider, TraceError> {
-    info!(
-        "Initializing OpenTelemetry with endpoint: {}",
-        config.collector_endpoint
-    );
-    let provider = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .http()
-                .with_endpoint(&config.collector_endpoint),
-        )
-        .with_trace_config(
-            sdktrace::config().with_resource(opentelemetry_sdk::Resource::new(vec![
-                opentelemetry::KeyValue::new("service.name", config.service_name.clone()),
-            ])),
-        )
-        .install_batch(runtime::Tokio)?;
+fn init_opentelemetry(
+    config: &Config,
+    resource: Resource,
+    pipelines: &mut Vec<Pipeline>,
+) -> Result<sdktrace::TracerProvider, TraceError> {
+    let trace_config = sdktrace::Config::default()
+        .with_resource(resource.clone())
+        .with_sampler(config.sampling.sampler());
+    let mut builder = sdktrace::TracerProvider::builder().with_config(trace_config);
+    let exporters = config.exporters(Signal::Traces);
+    if exporters.is_empty() {
+        info!("Trace export is disabled");
+    }
+    for exporter in exporters {
+        info!(
+            "Initializing OpenTelemetry for {} with endpoint: {} ({})",
+            exporter.destination, exporter.endpoint, exporter.protocol
+        );
+        let spool = Spool::open(&config.spill, &exporter);
+        let span_exporter =
+            SpanExporterBuilder::from(ExporterBuilder::new(&exporter, spool.clone()))
+                .build_span_exporter()?;
+        let span_exporter =
+            RetryingSpanExporter::new(span_exporter, exporter.retry).with_spill(spool.is_some());
+        let mut span_exporter = AdoptingSpanExporter::new(span_exporter);
+        span_exporter.set_resource(&resource);
+        let processor = Shared::new(
+            sdktrace::BatchSpanProcessor::builder(span_exporter, runtime::Tokio).build(),
+        );
+        pipelines.push(Pipeline::spans(&exporter.destination, processor.clone()).with_spool(spool));
+        builder = builder.with_span_processor(processor);
+    }
 
     info!("OpenTelemetry initialized successfully");
-    Ok(provider)
+    Ok(builder.build())
 }
-async fn handler(events: Vec<LambdaTelemetry>) -> Result<(), Error> {
-    debug!("Handler received {} events", events.len());
-    let tracer = global::tracer("lambda_extension");
-
-    tracer.in_span("handler", |cx| {
-        let span = cx.span();
-        for event in events {
-            match event.record {
-                LambdaTelemetryRecord::Function(record) => {
-                    info!("Function log received");
-                    let attributes = parse_function_log(&record);
-                    span.add_event("function_log", attributes);
-                }
-                LambdaTelemetryRecord::PlatformInitStart {
-                    initialization_type,
-                    phase,
-                    runtime_version,
-                    runtime_version_arn,
-                } => {
-                    info!("Platform init event: {:?}", initialization_type);
-                    span.add_event(
-                        "init_start".to_string(),
-                        vec![
-                            KeyValue::new("init_type", format!("{:?}", initialization_type)),
-                            KeyValue::new("phase", format!("{:?}", phase)),
-                            KeyValue::new("runtime_version", format!("{:?}", runtime_version)),
-                            KeyValue::new(
-                                "runtime_version_arn",
-                                format!("{:?}", runtime_version_arn),
-                            ),
-                        ],
-                    );
-                }
-                LambdaTelemetryRecord::PlatformInitRuntimeDone {
-                    initialization_type,
-                    phase,
-                    ..
-                } => {
-                    info!("Platform init done: {:?}", initialization_type);
-                    span.add_event(
-                        "init_runtime_done".to_string(),
-                        vec![
-                            KeyValue::new("init_type", format!("{:?}", initialization_type)),
-                            KeyValue::new("phase", format!("{:?}", phase)),
-                        ],
-                    )
-                }
-                LambdaTelemetryRecord::PlatformInitReport {
-                    initialization_type,
-                    metrics,
-                    phase,
-                    ..
-                } => {
-                    info!("Platform init report: {:?}", initialization_type);
-                    span.add_event(
-                        "init_report".to_string(),
-                        vec![
-                            KeyValue::new("init_type", format!("{:?}", initialization_type)),
-                            KeyValue::new("phase", format!("{:?}", phase)),
-                            KeyValue::new("duration", format!("{:?}", metrics.duration_ms)),
-                        ],
-                    );
-                }
-                LambdaTelemetryRecord::PlatformStart { request_id, .. } => {
-                    info!("Platform start event: {:?}", request_id);
-                    span.add_event(
-                        "platform_start".to_string(),
-                        vec![KeyValue::new("request_id", format!("{:?}", request_id))],
-                    );
-                }
-                LambdaTelemetryRecord::PlatformRuntimeDone {
-                    metrics,
-                    request_id,
-                    ..
-                } => {
-                    info!("Platform runtime done: {:?}", request_id);
-                    span.add_event(
-                        "runtime_done",
-                        vec![
-                            KeyValue::new("request_id", format!("{:?}", request_id)),
-                            KeyValue::new("duration", format!("{:?}", metrics)),
-                        ],
-                    )
-                }
-                LambdaTelemetryRecord::PlatformReport {
-                    metrics,
-                    request_id,
-                    ..
-                } => {
-                    info!("Platform report event: {:?}", request_id);
-                    span.add_event(
-                        "platform_report".to_string(),
-                        vec![
-                            KeyValue::new("request_id", format!("{:?}", request_id)),
-                            KeyValue::new("duration", format!("{:?}", metrics.duration_ms)),
-                        ],
-                    );
-                }
-                _ => {
-                    info!("Unhandled event: {:?}", event);
-                    span.add_event(
-                        "unhandled_event".to_string(),
-                        vec![KeyValue::new("event", format!("{:?}", event))],
-                    );
-                }
-            }
-        }
-    });
 
+fn init_metrics(
+    config: &Config,
+    resource: Resource,
+    pipelines: &mut Vec<Pipeline>,
+) -> Result<SdkMeterProvider, MetricsError> {
+    let mut builder = SdkMeterProvider::builder().with_resource(resource);
+    let exporters = config.exporters(Signal::Metrics);
+    if exporters.is_empty() {
+        info!("Metric export is disabled");
+    }
+    for exporter in exporters {
+        info!(
+            "Initializing metrics for {} with endpoint: {}",
+            exporter.destination, exporter.endpoint
+        );
+        let metrics_exporter = MetricsExporterBuilder::from(ExporterBuilder::new(&exporter, None))
+            .build_metrics_exporter(
+                Box::new(DefaultTemporalitySelector::new()),
+                Box::new(DefaultAggregationSelector::new()),
+            )?;
+        let metrics_exporter = RetryingMetricsExporter::new(metrics_exporter, exporter.retry);
+        let forwarded = ForwardedMetrics::default();
+        let metrics_exporter = ForwardingMetricsExporter::new(metrics_exporter, forwarded.clone());
+        let reader = Shared::new(PeriodicReader::builder(metrics_exporter, runtime::Tokio).build());
+        pipelines.push(Pipeline::metrics(
+            &exporter.destination,
+            reader.clone(),
+            forwarded,
+        ));
+        builder = builder.with_reader(reader);
+    }
+
+    info!("Metrics initialized successfully");
+    Ok(builder.build())
+}
+
+fn init_logs(
+    config: &Config,
+    resource: Resource,
+    pipelines: &mut Vec<Pipeline>,
+) -> Result<LoggerProvider, LogError> {
+    let mut builder = LoggerProvider::builder().with_resource(resource);
+    let exporters = config.exporters(Signal::Logs);
+    if exporters.is_empty() {
+        info!("Log export is disabled");
+    }
+    for exporter in exporters {
+        info!(
+            "Initializing logs for {} with endpoint: {}",
+            exporter.destination, exporter.endpoint
+        );
+        let spool = Spool::open(&config.spill, &exporter);
+        let log_exporter = LogExporterBuilder::from(ExporterBuilder::new(&exporter, spool.clone()))
+            .build_log_exporter()?;
+        let log_exporter = AdoptingLogExporter::new(
+            RetryingLogExporter::new(log_exporter, exporter.retry).with_spill(spool.is_some()),
+        );
+        let processor =
+            Shared::new(BatchLogProcessor::builder(log_exporter, runtime::Tokio).build());
+        pipelines.push(Pipeline::logs(&exporter.destination, processor.clone()).with_spool(spool));
+        builder = builder.with_log_processor(processor);
+    }
+
+    info!("Logs initialized successfully");
+    Ok(builder.build())
+}
+
+async fn events_handler(
+    flusher: Arc<tokio::sync::Mutex<Flusher>>,
+    coordinator: Arc<ShutdownCoordinator>,
+    event: LambdaEvent,
+) -> Result<(), Error> {
+    match event.next {
+        NextEvent::Invoke(invoke) => {
+            debug!("Invoke event: {}", invoke.request_id);
+            flusher
+                .lock()
+                .await
+                .invocation(&invoke.request_id, invoke.deadline_ms)
+                .await;
+        }
+        NextEvent::Shutdown(shutdown) => {
+            info!("Shutdown event: {:?}", shutdown.shutdown_reason);
+            coordinator.on_shutdown(&shutdown).await;
+        }
+    }
     Ok(())
 }
 
-fn parse_function_log(record: &str) -> Vec<KeyValue> {
-    if let Some(json_start) = record.find('{') {
-        let json_str = &record[json_start..];
-        match serde_json::from_str::<Value>(json_str) {
-            Ok(json) => {
-                let mut event_attributes = Vec::new();
-                if let Value::Object(map) = json {
-                    for (key, value) in map {
-                        let value_str = match value {
-                            Value::String(s) => s,
-                            _ => value.to_string(),
-                        };
-                        event_attributes.push(KeyValue::new(key, value_str));
-                    }
-                }
-                event_attributes
+async fn handler(
+    tracker: Arc<Mutex<InvocationTracker>>,
+    invocation_metrics: Arc<InvocationMetrics>,
+    log_emitter: Arc<LogEmitter>,
+    runtime_done: watch::Sender<Option<String>>,
+    events: Vec<LambdaTelemetry>,
+) -> Result<(), Error> {
+    debug!("Handler received {} events", events.len());
+    let mut tracker = tracker.lock().unwrap();
+    // Announced once the whole batch is handled, so a flush it lets go
+    // includes the records delivered after it.
+    let mut done = None;
+
+    for event in events {
+        let time = event.time.into();
+        match event.record {
+            LambdaTelemetryRecord::Function(record) => {
+                debug!("Function log received");
+                log_emitter.emit(LogSource::Function, time, &record, tracker.span_at(time));
+            }
+            LambdaTelemetryRecord::Extension(record) => {
+                debug!("Extension log received");
+                log_emitter.emit(LogSource::Extension, time, &record, tracker.span_at(time));
+            }
+            LambdaTelemetryRecord::PlatformInitStart {
+                initialization_type,
+                phase,
+                runtime_version,
+                runtime_version_arn,
+            } => {
+                info!("Platform init event: {:?}", initialization_type);
+                tracker.init_start(
+                    time,
+                    &initialization_type,
+                    &phase,
+                    runtime_version.as_deref(),
+                    runtime_version_arn.as_deref(),
+                );
+            }
+            LambdaTelemetryRecord::PlatformInitRuntimeDone {
+                initialization_type,
+                phase,
+                status,
+                error_type,
+                spans,
+            } => {
+                info!("Platform init done: {:?}", initialization_type);
+                let outcome = Outcome {
+                    status: &status,
+                    error_type: error_type.as_deref(),
+                };
+                tracker.init_runtime_done(
+                    time,
+                    &initialization_type,
+                    phase.as_ref(),
+                    outcome,
+                    &spans,
+                );
+            }
+            LambdaTelemetryRecord::PlatformInitReport {
+                initialization_type,
+                metrics,
+                phase,
+                spans,
+            } => {
+                info!("Platform init report: {:?}", initialization_type);
+                tracker.init_report(time, &initialization_type, &phase, &metrics, &spans);
+                invocation_metrics.record_init_report(&initialization_type, &metrics);
             }
-            Err(e) => {
-                warn!("Failed to parse JSON from function log: {:?}", e);
-                vec![
-                    KeyValue::new("error", format!("{:?}", e)),
-                    KeyValue::new("raw_log", record.to_string()),
-                ]
+            LambdaTelemetryRecord::PlatformStart {
+                request_id,
+                version,
+                tracing,
+            } => {
+                info!("Platform start event: {:?}", request_id);
+                tracker.start(&request_id, time, version.as_deref(), tracing.as_ref());
+            }
+            LambdaTelemetryRecord::PlatformRuntimeDone {
+                metrics,
+                request_id,
+                status,
+                error_type,
+                spans,
+                tracing,
+            } => {
+                info!("Platform runtime done: {:?}", request_id);
+                let outcome = Outcome {
+                    status: &status,
+                    error_type: error_type.as_deref(),
+                };
+                tracker.runtime_done(
+                    &request_id,
+                    time,
+                    metrics.as_ref(),
+                    outcome,
+                    &spans,
+                    tracing.as_ref(),
+                );
+                done = Some(request_id);
+            }
+            LambdaTelemetryRecord::PlatformReport {
+                metrics,
+                request_id,
+                status,
+                error_type,
+                spans,
+                tracing,
+            } => {
+                info!("Platform report event: {:?}", request_id);
+                let outcome = Outcome {
+                    status: &status,
+                    error_type: error_type.as_deref(),
+                };
+                tracker.report(
+                    &request_id,
+                    time,
+                    &metrics,
+                    outcome,
+                    &spans,
+                    tracing.as_ref(),
+                );
+                invocation_metrics.record_report(&metrics);
+            }
+            _ => {
+                info!("Unhandled event: {:?}", event);
+                let line = serde_json::to_string(&event).unwrap_or_default();
+                log_emitter.emit(LogSource::Platform, time, &line, None);
             }
         }
-    } else {
-        warn!("No JSON found in function log");
-        vec![KeyValue::new("raw_log", record.to_string())]
     }
+    if done.is_some() {
+        runtime_done.send_replace(done);
+    }
+
+    Ok(())
 }
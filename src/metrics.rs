@@ -0,0 +1,146 @@
+use crate::semconv;
+use lambda_extension::{InitReportMetrics, InitType, ReportMetrics};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Gauge, Histogram, Meter},
+    KeyValue,
+};
+use std::env;
+use std::sync::OnceLock;
+
+/// Instruments recording the metrics Lambda reports for every invocation and
+/// init phase, tagged with the function name and version.
+pub struct InvocationMetrics {
+    duration: Histogram<f64>,
+    billed_duration: Histogram<u64>,
+    init_duration: Histogram<f64>,
+    restore_duration: Histogram<f64>,
+    memory_used: Gauge<u64>,
+    memory_utilization: Gauge<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl InvocationMetrics {
+    pub fn new(meter: Meter) -> Self {
+        let mut attributes = Vec::new();
+        if let Ok(name) = env::var("AWS_LAMBDA_FUNCTION_NAME") {
+            attributes.push(KeyValue::new(semconv::FAAS_NAME, name));
+        }
+        if let Ok(version) = env::var("AWS_LAMBDA_FUNCTION_VERSION") {
+            attributes.push(KeyValue::new(semconv::FAAS_VERSION, version));
+        }
+
+        InvocationMetrics {
+            duration: meter
+                .f64_histogram("aws.lambda.invocation.duration")
+                .with_description("Duration of the invocation as reported by platform.report")
+                .with_unit("ms")
+                .init(),
+            billed_duration: meter
+                .u64_histogram("aws.lambda.invocation.billed_duration")
+                .with_description("Billed duration of the invocation")
+                .with_unit("ms")
+                .init(),
+            init_duration: meter
+                .f64_histogram("aws.lambda.init.duration")
+                .with_description("Duration of the init phase")
+                .with_unit("ms")
+                .init(),
+            restore_duration: meter
+                .f64_histogram("aws.lambda.restore.duration")
+                .with_description("Duration of a SnapStart restore")
+                .with_unit("ms")
+                .init(),
+            memory_used: meter
+                .u64_gauge("aws.lambda.memory.used")
+                .with_description("Maximum memory used by the invocation")
+                .with_unit("By")
+                .init(),
+            memory_utilization: meter
+                .f64_gauge("aws.lambda.memory.utilization")
+                .with_description("Maximum memory used as a fraction of the configured memory")
+                .with_unit("1")
+                .init(),
+            attributes,
+        }
+    }
+
+    pub fn record_report(&self, metrics: &ReportMetrics) {
+        let attributes = self.attributes.as_slice();
+        self.duration.record(metrics.duration_ms, attributes);
+        self.billed_duration
+            .record(metrics.billed_duration_ms, attributes);
+        self.memory_used.record(
+            semconv::mb_to_bytes(metrics.max_memory_used_mb) as u64,
+            attributes,
+        );
+        if metrics.memory_size_mb > 0 {
+            self.memory_utilization.record(
+                metrics.max_memory_used_mb as f64 / metrics.memory_size_mb as f64,
+                attributes,
+            );
+        }
+        if let Some(restore_duration_ms) = metrics.restore_duration_ms {
+            self.restore_duration
+                .record(restore_duration_ms, attributes);
+        }
+    }
+
+    pub fn record_init_report(&self, initialization_type: &InitType, metrics: &InitReportMetrics) {
+        let mut attributes = self.attributes.clone();
+        attributes.push(KeyValue::new(
+            semconv::AWS_LAMBDA_INITIALIZATION_TYPE,
+            semconv::init_type_name(initialization_type),
+        ));
+        self.init_duration.record(metrics.duration_ms, &attributes);
+    }
+}
+
+/// Requests made by the extension's own exports and, for HTTP, their body
+/// sizes before and after compression, tagged with the destination, signal
+/// and encoding.
+pub struct ExportMetrics {
+    requests: Counter<u64>,
+    uncompressed_bytes: Counter<u64>,
+    bytes: Counter<u64>,
+}
+
+impl ExportMetrics {
+    /// The instruments, created on first use. Nothing is exported before
+    /// the meter provider is installed, so they are not created earlier.
+    pub fn global() -> &'static ExportMetrics {
+        static METRICS: OnceLock<ExportMetrics> = OnceLock::new();
+        METRICS.get_or_init(|| ExportMetrics::new(global::meter("lambda_extension")))
+    }
+
+    fn new(meter: Meter) -> Self {
+        ExportMetrics {
+            requests: meter
+                .u64_counter("microfiber.export.requests")
+                .with_description("OTLP export requests attempted, including retries")
+                .init(),
+            uncompressed_bytes: meter
+                .u64_counter("microfiber.export.uncompressed_bytes")
+                .with_description("Size of OTLP request bodies before compression")
+                .with_unit("By")
+                .init(),
+            bytes: meter
+                .u64_counter("microfiber.export.bytes")
+                .with_description("Size of OTLP request bodies as sent")
+                .with_unit("By")
+                .init(),
+        }
+    }
+
+    pub fn record(&self, uncompressed: usize, sent: usize, attributes: &[KeyValue]) {
+        self.requests.add(1, attributes);
+        self.uncompressed_bytes.add(uncompressed as u64, attributes);
+        self.bytes.add(sent as u64, attributes);
+    }
+
+    /// Counts a gRPC request, whose body tonic encodes and compresses only
+    /// after the interceptors have seen it, so its size is not recorded.
+    pub fn record_request(&self, attributes: &[KeyValue]) {
+        self.requests.add(1, attributes);
+    }
+}
@@ -0,0 +1,708 @@
+//! Conversions from the OTLP messages the function exports to the SDK's
+//! types, so they can go through the same processors and exporters as the
+//! extension's own telemetry. The function's resource is not kept: the
+//! exporters attach the extension's, which describes the same function, and
+//! only the service name is taken from it when none is configured.
+
+use opentelemetry::logs::{AnyValue as LogValue, LogRecord as _, Severity};
+use opentelemetry::trace::{
+    Event, Link, SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceId, TraceState,
+};
+use opentelemetry::{Array, InstrumentationLibrary, Key, KeyValue, StringValue, Value};
+use opentelemetry_proto::tonic::common::v1::{self as common, any_value};
+use opentelemetry_proto::tonic::logs::v1 as logs;
+use opentelemetry_proto::tonic::metrics::v1::{self as metrics, metric, number_data_point};
+use opentelemetry_proto::tonic::resource::v1 as resource;
+use opentelemetry_proto::tonic::trace::v1::{self as trace, span, status};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::logs::{LogRecord, TraceContext};
+use opentelemetry_sdk::metrics::data::{self, Temporality};
+use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The `service.name` of a resource the function reported, if it has one.
+pub fn service_name(resource: &resource::Resource) -> Option<String> {
+    resource
+        .attributes
+        .iter()
+        .find(|attribute| attribute.key == "service.name")
+        .and_then(
+            |attribute| match attribute.value.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(name) => Some(name.clone()),
+                _ => None,
+            },
+        )
+}
+
+/// The spans in an export request. Spans without valid ids are skipped.
+pub fn spans(resource_spans: Vec<trace::ResourceSpans>) -> Vec<SpanData> {
+    let mut spans = Vec::new();
+    for scope_spans in resource_spans.into_iter().flat_map(|r| r.scope_spans) {
+        let library = library(scope_spans.scope);
+        spans.extend(
+            scope_spans
+                .spans
+                .into_iter()
+                .filter_map(|span| span_data(span, &library)),
+        );
+    }
+    spans
+}
+
+fn span_data(span: trace::Span, library: &InstrumentationLibrary) -> Option<SpanData> {
+    let context = span_context(&span.trace_id, &span.span_id, span.flags, &span.trace_state)?;
+    let mut events = SpanEvents::default();
+    events.events = span
+        .events
+        .into_iter()
+        .map(|event| {
+            Event::new(
+                event.name,
+                time(event.time_unix_nano),
+                attributes(event.attributes),
+                event.dropped_attributes_count,
+            )
+        })
+        .collect();
+    events.dropped_count = span.dropped_events_count;
+    let mut links = SpanLinks::default();
+    links.links = span
+        .links
+        .into_iter()
+        .filter_map(|link| {
+            let context =
+                span_context(&link.trace_id, &link.span_id, link.flags, &link.trace_state)?;
+            Some(Link::new(
+                context,
+                attributes(link.attributes),
+                link.dropped_attributes_count,
+            ))
+        })
+        .collect();
+    links.dropped_count = span.dropped_links_count;
+    let status = match span.status {
+        Some(s) if s.code == status::StatusCode::Error as i32 => Status::error(s.message),
+        Some(s) if s.code == status::StatusCode::Ok as i32 => Status::Ok,
+        _ => Status::Unset,
+    };
+    Some(SpanData {
+        span_context: context,
+        parent_span_id: span_id(&span.parent_span_id).unwrap_or(SpanId::INVALID),
+        span_kind: match span::SpanKind::try_from(span.kind) {
+            Ok(span::SpanKind::Server) => SpanKind::Server,
+            Ok(span::SpanKind::Client) => SpanKind::Client,
+            Ok(span::SpanKind::Producer) => SpanKind::Producer,
+            Ok(span::SpanKind::Consumer) => SpanKind::Consumer,
+            _ => SpanKind::Internal,
+        },
+        name: span.name.into(),
+        start_time: time(span.start_time_unix_nano),
+        end_time: time(span.end_time_unix_nano),
+        attributes: attributes(span.attributes),
+        dropped_attributes_count: span.dropped_attributes_count,
+        events,
+        links,
+        status,
+        instrumentation_lib: library.clone(),
+    })
+}
+
+/// The log records in an export request, with their instrumentation scope.
+pub fn log_records(
+    resource_logs: Vec<logs::ResourceLogs>,
+) -> Vec<(LogRecord, InstrumentationLibrary)> {
+    let mut records = Vec::new();
+    for scope_logs in resource_logs.into_iter().flat_map(|r| r.scope_logs) {
+        let library = library(scope_logs.scope);
+        for record in scope_logs.log_records {
+            records.push((log_record(record), library.clone()));
+        }
+    }
+    records
+}
+
+fn log_record(record: logs::LogRecord) -> LogRecord {
+    let mut log = LogRecord::default();
+    log.timestamp = (record.time_unix_nano > 0).then(|| time(record.time_unix_nano));
+    log.observed_timestamp = Some(match record.observed_time_unix_nano {
+        0 => SystemTime::now(),
+        nanos => time(nanos),
+    });
+    if let Some(severity) = severity(record.severity_number) {
+        log.severity_number = Some(severity);
+        log.severity_text = Some(severity.name());
+    }
+    log.body = record.body.map(|body| log_value(Some(body)));
+    for attribute in record.attributes {
+        log.add_attribute(Key::new(attribute.key), log_value(attribute.value));
+    }
+    if let Some(context) = span_context(&record.trace_id, &record.span_id, record.flags, "") {
+        log.trace_context = Some(TraceContext::from(&context));
+    }
+    log
+}
+
+fn severity(number: i32) -> Option<Severity> {
+    const SEVERITIES: [Severity; 24] = [
+        Severity::Trace,
+        Severity::Trace2,
+        Severity::Trace3,
+        Severity::Trace4,
+        Severity::Debug,
+        Severity::Debug2,
+        Severity::Debug3,
+        Severity::Debug4,
+        Severity::Info,
+        Severity::Info2,
+        Severity::Info3,
+        Severity::Info4,
+        Severity::Warn,
+        Severity::Warn2,
+        Severity::Warn3,
+        Severity::Warn4,
+        Severity::Error,
+        Severity::Error2,
+        Severity::Error3,
+        Severity::Error4,
+        Severity::Fatal,
+        Severity::Fatal2,
+        Severity::Fatal3,
+        Severity::Fatal4,
+    ];
+    let index = usize::try_from(number).ok()?.checked_sub(1)?;
+    SEVERITIES.get(index).copied()
+}
+
+/// The metrics in an export request, grouped by instrumentation scope.
+/// Summaries, which the SDK has no type for, are skipped.
+pub fn scope_metrics(resource_metrics: Vec<metrics::ResourceMetrics>) -> Vec<data::ScopeMetrics> {
+    resource_metrics
+        .into_iter()
+        .flat_map(|r| r.scope_metrics)
+        .map(|scope_metrics| data::ScopeMetrics {
+            scope: library(scope_metrics.scope),
+            metrics: scope_metrics
+                .metrics
+                .into_iter()
+                .filter_map(metric)
+                .collect(),
+        })
+        .collect()
+}
+
+fn metric(metric: metrics::Metric) -> Option<data::Metric> {
+    let data: Box<dyn data::Aggregation> = match metric.data? {
+        metric::Data::Gauge(gauge) => match number_points(gauge.data_points) {
+            Points::Int(data_points) => Box::new(data::Gauge { data_points }),
+            Points::Double(data_points) => Box::new(data::Gauge { data_points }),
+        },
+        metric::Data::Sum(sum) => {
+            let temporality = temporality(sum.aggregation_temporality);
+            let is_monotonic = sum.is_monotonic;
+            match number_points(sum.data_points) {
+                Points::Int(data_points) => Box::new(data::Sum {
+                    data_points,
+                    temporality,
+                    is_monotonic,
+                }),
+                Points::Double(data_points) => Box::new(data::Sum {
+                    data_points,
+                    temporality,
+                    is_monotonic,
+                }),
+            }
+        }
+        metric::Data::Histogram(histogram) => Box::new(data::Histogram {
+            temporality: temporality(histogram.aggregation_temporality),
+            data_points: histogram
+                .data_points
+                .into_iter()
+                .map(|point| data::HistogramDataPoint {
+                    attributes: attributes(point.attributes),
+                    start_time: time(point.start_time_unix_nano),
+                    time: time(point.time_unix_nano),
+                    count: point.count,
+                    bounds: point.explicit_bounds,
+                    bucket_counts: point.bucket_counts,
+                    min: point.min,
+                    max: point.max,
+                    sum: point.sum.unwrap_or_default(),
+                    exemplars: Vec::new(),
+                })
+                .collect(),
+        }),
+        metric::Data::ExponentialHistogram(histogram) => Box::new(data::ExponentialHistogram {
+            temporality: temporality(histogram.aggregation_temporality),
+            data_points: histogram
+                .data_points
+                .into_iter()
+                .map(|point| data::ExponentialHistogramDataPoint {
+                    attributes: attributes(point.attributes),
+                    start_time: time(point.start_time_unix_nano),
+                    time: time(point.time_unix_nano),
+                    count: point.count as usize,
+                    min: point.min,
+                    max: point.max,
+                    sum: point.sum.unwrap_or_default(),
+                    scale: point.scale.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+                    zero_count: point.zero_count,
+                    positive_bucket: bucket(point.positive),
+                    negative_bucket: bucket(point.negative),
+                    zero_threshold: point.zero_threshold,
+                    exemplars: Vec::new(),
+                })
+                .collect(),
+        }),
+        metric::Data::Summary(_) => return None,
+    };
+    Some(data::Metric {
+        name: metric.name.into(),
+        description: metric.description.into(),
+        unit: metric.unit.into(),
+        data,
+    })
+}
+
+enum Points {
+    Int(Vec<data::DataPoint<i64>>),
+    Double(Vec<data::DataPoint<f64>>),
+}
+
+// Integer points stay integers unless the metric mixes in doubles.
+fn number_points(points: Vec<metrics::NumberDataPoint>) -> Points {
+    let all_ints = points
+        .iter()
+        .all(|point| matches!(point.value, Some(number_data_point::Value::AsInt(_))));
+    if all_ints {
+        Points::Int(
+            points
+                .into_iter()
+                .map(|point| {
+                    let value = match point.value {
+                        Some(number_data_point::Value::AsInt(value)) => value,
+                        _ => 0,
+                    };
+                    data_point(point, value)
+                })
+                .collect(),
+        )
+    } else {
+        Points::Double(
+            points
+                .into_iter()
+                .map(|point| {
+                    let value = match point.value {
+                        Some(number_data_point::Value::AsInt(value)) => value as f64,
+                        Some(number_data_point::Value::AsDouble(value)) => value,
+                        None => 0.0,
+                    };
+                    data_point(point, value)
+                })
+                .collect(),
+        )
+    }
+}
+
+fn data_point<T>(point: metrics::NumberDataPoint, value: T) -> data::DataPoint<T> {
+    data::DataPoint {
+        attributes: attributes(point.attributes),
+        start_time: (point.start_time_unix_nano > 0).then(|| time(point.start_time_unix_nano)),
+        time: (point.time_unix_nano > 0).then(|| time(point.time_unix_nano)),
+        value,
+        exemplars: Vec::new(),
+    }
+}
+
+fn bucket(
+    buckets: Option<metrics::exponential_histogram_data_point::Buckets>,
+) -> data::ExponentialBucket {
+    let buckets = buckets.unwrap_or_default();
+    data::ExponentialBucket {
+        offset: buckets.offset,
+        counts: buckets.bucket_counts,
+    }
+}
+
+fn temporality(temporality: i32) -> Temporality {
+    if temporality == metrics::AggregationTemporality::Delta as i32 {
+        Temporality::Delta
+    } else {
+        Temporality::Cumulative
+    }
+}
+
+fn library(scope: Option<common::InstrumentationScope>) -> InstrumentationLibrary {
+    let scope = scope.unwrap_or_default();
+    let mut builder = InstrumentationLibrary::builder(scope.name);
+    if !scope.version.is_empty() {
+        builder = builder.with_version(scope.version);
+    }
+    builder
+        .with_attributes(attributes(scope.attributes))
+        .build()
+}
+
+fn span_context(
+    trace_id: &[u8],
+    span_id: &[u8],
+    flags: u32,
+    trace_state: &str,
+) -> Option<SpanContext> {
+    let trace_id = TraceId::from_bytes(trace_id.try_into().ok()?);
+    let span_id = self::span_id(span_id)?;
+    if trace_id == TraceId::INVALID {
+        return None;
+    }
+    // The function only exports what it sampled, whatever the flags say.
+    let flags = TraceFlags::new(flags as u8).with_sampled(true);
+    let trace_state = TraceState::from_str(trace_state).unwrap_or_default();
+    Some(SpanContext::new(
+        trace_id,
+        span_id,
+        flags,
+        false,
+        trace_state,
+    ))
+}
+
+fn span_id(bytes: &[u8]) -> Option<SpanId> {
+    let span_id = SpanId::from_bytes(bytes.try_into().ok()?);
+    (span_id != SpanId::INVALID).then_some(span_id)
+}
+
+fn time(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+fn attributes(attributes: Vec<common::KeyValue>) -> Vec<KeyValue> {
+    attributes
+        .into_iter()
+        .map(|attribute| KeyValue::new(attribute.key, value(attribute.value)))
+        .collect()
+}
+
+// Span and metric attributes only hold primitives and arrays of them, so
+// anything else is kept as JSON.
+fn value(value: Option<common::AnyValue>) -> Value {
+    let Some(value) = value.and_then(|value| value.value) else {
+        return Value::from("");
+    };
+    match value {
+        any_value::Value::StringValue(s) => Value::from(s),
+        any_value::Value::BoolValue(b) => Value::from(b),
+        any_value::Value::IntValue(i) => Value::from(i),
+        any_value::Value::DoubleValue(d) => Value::from(d),
+        any_value::Value::ArrayValue(array) => {
+            array_value(&array.values).unwrap_or_else(|| json(any_value::Value::ArrayValue(array)))
+        }
+        other => json(other),
+    }
+}
+
+fn array_value(values: &[common::AnyValue]) -> Option<Value> {
+    let values: Vec<&any_value::Value> = values.iter().filter_map(|v| v.value.as_ref()).collect();
+    let array = match values.first() {
+        None => Array::String(Vec::new()),
+        Some(any_value::Value::StringValue(_)) => Array::String(
+            values
+                .iter()
+                .map(|v| match v {
+                    any_value::Value::StringValue(s) => Some(StringValue::from(s.clone())),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+        ),
+        Some(any_value::Value::BoolValue(_)) => Array::Bool(
+            values
+                .iter()
+                .map(|v| match v {
+                    any_value::Value::BoolValue(b) => Some(*b),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+        ),
+        Some(any_value::Value::IntValue(_)) => Array::I64(
+            values
+                .iter()
+                .map(|v| match v {
+                    any_value::Value::IntValue(i) => Some(*i),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+        ),
+        Some(any_value::Value::DoubleValue(_)) => Array::F64(
+            values
+                .iter()
+                .map(|v| match v {
+                    any_value::Value::DoubleValue(d) => Some(*d),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+        ),
+        Some(_) => return None,
+    };
+    Some(Value::Array(array))
+}
+
+fn json(value: any_value::Value) -> Value {
+    Value::from(serde_json::to_string(&value).unwrap_or_default())
+}
+
+fn log_value(value: Option<common::AnyValue>) -> LogValue {
+    let Some(value) = value.and_then(|value| value.value) else {
+        return LogValue::from(String::new());
+    };
+    match value {
+        any_value::Value::StringValue(s) => LogValue::from(s),
+        any_value::Value::BoolValue(b) => LogValue::from(b),
+        any_value::Value::IntValue(i) => LogValue::from(i),
+        any_value::Value::DoubleValue(d) => LogValue::from(d),
+        any_value::Value::BytesValue(bytes) => LogValue::Bytes(Box::new(bytes)),
+        any_value::Value::ArrayValue(array) => LogValue::ListAny(Box::new(
+            array
+                .values
+                .into_iter()
+                .map(|v| log_value(Some(v)))
+                .collect(),
+        )),
+        any_value::Value::KvlistValue(list) => LogValue::Map(Box::new(
+            list.values
+                .into_iter()
+                .map(|kv| (Key::new(kv.key), log_value(kv.value)))
+                .collect::<HashMap<_, _>>(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::logs::AnyValue;
+
+    fn string(value: &str) -> Option<common::AnyValue> {
+        Some(common::AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        })
+    }
+
+    fn int(value: i64) -> common::AnyValue {
+        common::AnyValue {
+            value: Some(any_value::Value::IntValue(value)),
+        }
+    }
+
+    fn key_value(key: &str, value: Option<common::AnyValue>) -> common::KeyValue {
+        common::KeyValue {
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    fn resource_spans(spans: Vec<trace::Span>) -> Vec<trace::ResourceSpans> {
+        vec![trace::ResourceSpans {
+            scope_spans: vec![trace::ScopeSpans {
+                scope: Some(common::InstrumentationScope {
+                    name: "handler".to_string(),
+                    version: "1.2.0".to_string(),
+                    ..Default::default()
+                }),
+                spans,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn finds_the_service_name() {
+        let resource = resource::Resource {
+            attributes: vec![
+                key_value("cloud.provider", string("aws")),
+                key_value("service.name", string("checkout")),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(service_name(&resource).as_deref(), Some("checkout"));
+
+        let resource = resource::Resource {
+            attributes: vec![key_value("service.name", Some(int(1)))],
+            ..Default::default()
+        };
+        assert_eq!(service_name(&resource), None);
+    }
+
+    #[test]
+    fn converts_spans() {
+        let span = trace::Span {
+            trace_id: vec![1; 16],
+            span_id: vec![2; 8],
+            parent_span_id: vec![3; 8],
+            trace_state: "vendor=value".to_string(),
+            name: "GET /items".to_string(),
+            kind: span::SpanKind::Client as i32,
+            start_time_unix_nano: 1_700_000_000_000_000_001,
+            end_time_unix_nano: 1_700_000_000_500_000_000,
+            attributes: vec![key_value("http.method", string("GET"))],
+            dropped_attributes_count: 2,
+            events: vec![trace::span::Event {
+                time_unix_nano: 1_700_000_000_100_000_000,
+                name: "retry".to_string(),
+                ..Default::default()
+            }],
+            links: vec![trace::span::Link {
+                trace_id: vec![4; 16],
+                span_id: vec![5; 8],
+                ..Default::default()
+            }],
+            status: Some(trace::Status {
+                message: "timed out".to_string(),
+                code: status::StatusCode::Error as i32,
+            }),
+            ..Default::default()
+        };
+        let spans = spans(resource_spans(vec![span]));
+        let [span] = spans.as_slice() else {
+            panic!("expected one span, got {:?}", spans);
+        };
+
+        assert_eq!(span.span_context.trace_id(), TraceId::from_bytes([1; 16]));
+        assert_eq!(span.span_context.span_id(), SpanId::from_bytes([2; 8]));
+        assert!(span.span_context.is_sampled());
+        assert!(!span.span_context.is_remote());
+        assert_eq!(span.span_context.trace_state().get("vendor"), Some("value"));
+        assert_eq!(span.parent_span_id, SpanId::from_bytes([3; 8]));
+        assert_eq!(span.name, "GET /items");
+        assert_eq!(span.span_kind, SpanKind::Client);
+        assert_eq!(
+            span.start_time,
+            UNIX_EPOCH + Duration::new(1_700_000_000, 1)
+        );
+        assert_eq!(
+            span.end_time,
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)
+        );
+        assert_eq!(span.attributes, vec![KeyValue::new("http.method", "GET")]);
+        assert_eq!(span.dropped_attributes_count, 2);
+        assert_eq!(span.events.events[0].name, "retry");
+        assert_eq!(
+            span.links.links[0].span_context.trace_id(),
+            TraceId::from_bytes([4; 16])
+        );
+        assert_eq!(span.status, Status::error("timed out"));
+        assert_eq!(span.instrumentation_lib.name, "handler");
+        assert_eq!(span.instrumentation_lib.version.as_deref(), Some("1.2.0"));
+    }
+
+    #[test]
+    fn skips_spans_without_valid_ids() {
+        let span = |trace_id: Vec<u8>, span_id: Vec<u8>| trace::Span {
+            trace_id,
+            span_id,
+            ..Default::default()
+        };
+        let spans = spans(resource_spans(vec![
+            span(vec![0; 16], vec![2; 8]),
+            span(vec![1; 16], vec![0; 8]),
+            span(vec![1; 8], vec![2; 8]),
+            span(vec![1; 16], vec![2; 4]),
+            span(vec![1; 16], vec![2; 8]),
+        ]));
+        assert_eq!(spans.len(), 1);
+        // A root span has no parent id at all.
+        assert_eq!(spans[0].parent_span_id, SpanId::INVALID);
+        assert_eq!(spans[0].status, Status::Unset);
+        assert_eq!(spans[0].span_kind, SpanKind::Internal);
+    }
+
+    #[test]
+    fn converts_attribute_values() {
+        let array = |values: Vec<common::AnyValue>| {
+            Some(common::AnyValue {
+                value: Some(any_value::Value::ArrayValue(common::ArrayValue { values })),
+            })
+        };
+        assert_eq!(value(string("a")), Value::from("a"));
+        assert_eq!(value(Some(int(3))), Value::from(3));
+        assert_eq!(value(None), Value::from(""));
+        assert_eq!(
+            value(array(vec![int(1), int(2)])),
+            Value::Array(Array::I64(vec![1, 2]))
+        );
+        // Arrays mixing types and maps have no SDK equivalent.
+        assert_eq!(
+            value(array(vec![int(1), string("b").unwrap()])),
+            Value::from(
+                r#"{"arrayValue":{"values":[{"value":{"intValue":1}},{"value":{"stringValue":"b"}}]}}"#
+            )
+        );
+        let map = Some(common::AnyValue {
+            value: Some(any_value::Value::KvlistValue(common::KeyValueList {
+                values: vec![key_value("k", string("v"))],
+            })),
+        });
+        assert_eq!(
+            value(map),
+            Value::from(r#"{"kvlistValue":{"values":[{"key":"k","value":{"stringValue":"v"}}]}}"#)
+        );
+    }
+
+    #[test]
+    fn converts_log_records() {
+        let record = logs::LogRecord {
+            time_unix_nano: 1_700_000_000_000_000_000,
+            severity_number: logs::SeverityNumber::Warn2 as i32,
+            body: string("disk almost full"),
+            attributes: vec![key_value("disk", string("/tmp"))],
+            trace_id: vec![1; 16],
+            span_id: vec![2; 8],
+            flags: 1,
+            ..Default::default()
+        };
+        let records = log_records(vec![logs::ResourceLogs {
+            scope_logs: vec![logs::ScopeLogs {
+                scope: Some(common::InstrumentationScope {
+                    name: "app".to_string(),
+                    ..Default::default()
+                }),
+                log_records: vec![record, logs::LogRecord::default()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]);
+        let [(record, library), (empty, _)] = records.as_slice() else {
+            panic!("expected two records, got {}", records.len());
+        };
+
+        assert_eq!(library.name, "app");
+        assert_eq!(
+            record.timestamp,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(record.severity_number, Some(Severity::Warn2));
+        assert_eq!(record.severity_text, Some("WARN2"));
+        assert_eq!(record.body, Some(AnyValue::from("disk almost full")));
+        let trace_context = record.trace_context.as_ref().unwrap();
+        assert_eq!(trace_context.trace_id, TraceId::from_bytes([1; 16]));
+        assert_eq!(trace_context.span_id, SpanId::from_bytes([2; 8]));
+
+        // Missing fields stay missing, except when the record was observed.
+        assert_eq!(empty.timestamp, None);
+        assert!(empty.observed_timestamp.is_some());
+        assert_eq!(empty.severity_number, None);
+        assert_eq!(empty.body, None);
+        assert!(empty.trace_context.is_none());
+    }
+
+    #[test]
+    fn maps_every_severity_number() {
+        assert_eq!(severity(0), None);
+        assert_eq!(severity(1), Some(Severity::Trace));
+        assert_eq!(severity(9), Some(Severity::Info));
+        assert_eq!(severity(17), Some(Severity::Error));
+        assert_eq!(severity(24), Some(Severity::Fatal4));
+        assert_eq!(severity(25), None);
+        assert_eq!(severity(-1), None);
+    }
+}
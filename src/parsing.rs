@@ -0,0 +1,937 @@
+//! Parsers that pull the body, timestamp, severity and attributes out of the
+//! log lines a function writes, in the formats Lambda runtimes and common
+//! logging libraries use.
+
+use crate::semconv;
+use chrono::{DateTime, NaiveDateTime};
+use opentelemetry::{
+    logs::{AnyValue, Severity},
+    Key,
+};
+use serde_json::{Map, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Fields that carry the message, timestamp, level and request id in
+// structured lines, most common first.
+const MESSAGE_FIELDS: [&str; 2] = ["message", "msg"];
+const TIME_FIELDS: [&str; 4] = ["timestamp", "time", "@timestamp", "ts"];
+const LEVEL_FIELDS: [&str; 4] = ["level", "severity", "levelname", "log.level"];
+const REQUEST_ID_FIELDS: [&str; 2] = ["requestId", "AWSRequestId"];
+
+/// What a parser found in a log line. Anything it did not find is left to
+/// the Telemetry API record: the line itself as the body and the time
+/// Lambda received it.
+#[derive(Debug, Default)]
+pub struct ParsedLog {
+    pub body: Option<AnyValue>,
+    pub timestamp: Option<SystemTime>,
+    pub severity: Option<Severity>,
+    pub request_id: Option<String>,
+    pub attributes: Vec<(Key, AnyValue)>,
+}
+
+/// Extracts structure from log lines in one format.
+pub trait LogParser: Send + Sync {
+    /// Parses `line`, or returns `None` when it is not in this format.
+    fn parse(&self, line: &str) -> Option<ParsedLog>;
+}
+
+/// A JSON object per line, as written by Lambda's JSON log format and by
+/// structured loggers such as pino, bunyan, winston or Powertools.
+pub struct JsonParser;
+
+impl LogParser for JsonParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        match serde_json::from_str(line.trim()) {
+            Ok(Value::Object(fields)) => Some(from_fields(fields)),
+            _ => None,
+        }
+    }
+}
+
+/// The tab-separated prefix the managed runtimes put on what the function
+/// prints: `<time>\t<request id>\t<LEVEL>\t<message>` for Node.js and
+/// `[<LEVEL>]\t<time>\t<request id>\t<message>` for Python. A message that
+/// is itself JSON is parsed as well.
+pub struct LambdaParser;
+
+impl LogParser for LambdaParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut fields = line.splitn(4, '\t');
+        let first = fields.next()?;
+        let (level, time) = match first.strip_prefix('[').and_then(|f| f.strip_suffix(']')) {
+            Some(level) => (Some(level), fields.next()?),
+            None => (None, first),
+        };
+        let timestamp = parse_time(time)?;
+        let mut rest: Vec<&str> = fields.collect();
+        let request_id = match rest.first() {
+            Some(id) if is_request_id(id) => Some(rest.remove(0).to_string()),
+            _ => None,
+        };
+        let level = match level {
+            None if rest.len() >= 2 && severity_from_level(rest[0]).is_some() => {
+                Some(rest.remove(0))
+            }
+            level => level,
+        };
+        let message = rest.join("\t");
+        if request_id.is_none() && level.is_none() {
+            return None;
+        }
+
+        let mut parsed = JsonParser.parse(&message).unwrap_or_default();
+        if parsed.body.is_none() {
+            parsed.body = Some(AnyValue::from(message));
+        }
+        parsed.timestamp = parsed.timestamp.or(Some(timestamp));
+        parsed.severity = parsed.severity.or(level.and_then(severity_from_level));
+        parsed.request_id = parsed.request_id.or(request_id);
+        Some(parsed)
+    }
+}
+
+/// `key=value` pairs, with double quotes around values that have spaces.
+pub struct LogfmtParser;
+
+impl LogParser for LogfmtParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let mut fields = Map::new();
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            let (key, after) = rest.split_once('=')?;
+            if key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '@'))
+            {
+                return None;
+            }
+            let (value, after) = match after.strip_prefix('"') {
+                Some(quoted) => unquote(quoted)?,
+                None => after
+                    .split_once(' ')
+                    .map_or((after.to_string(), ""), |(value, after)| {
+                        (value.to_string(), after)
+                    }),
+            };
+            fields.insert(key.to_string(), Value::String(value));
+            rest = after.trim_start();
+        }
+        // A single pair is more likely prose that happens to contain `=`.
+        (fields.len() >= 2).then(|| from_fields(fields))
+    }
+}
+
+/// Python's `logging` defaults: `LEVEL:logger:message` from `basicConfig`
+/// and `<asctime> - <logger> - <LEVEL> - <message>`.
+pub struct PythonParser;
+
+impl LogParser for PythonParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let line = line.trim_end();
+        let mut parts = line.splitn(4, " - ");
+        if let (Some(time), Some(logger), Some(level), Some(message)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            if let (Some(timestamp), Some(severity)) =
+                (parse_time(time), severity_from_level(level))
+            {
+                return Some(text_log(message, Some(timestamp), severity, logger, None));
+            }
+        }
+        let mut parts = line.splitn(3, ':');
+        let (level, logger, message) = (parts.next()?, parts.next()?, parts.next()?);
+        if level != level.to_ascii_uppercase() || logger.contains(char::is_whitespace) {
+            return None;
+        }
+        let severity = severity_from_level(level)?;
+        Some(text_log(message, None, severity, logger, None))
+    }
+}
+
+/// The default Logback and Log4j layouts,
+/// `<date> <time> [<thread>] <LEVEL> <logger> - <message>`, and the Lambda
+/// Log4j appender's, which has the request id in place of the thread.
+pub struct JavaParser;
+
+impl LogParser for JavaParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let (date, rest) = next_token(line.trim_end())?;
+        let (time, rest) = next_token(rest)?;
+        let timestamp = parse_time(&format!("{} {}", date, time))?;
+        let (mut thread, mut request_id) = (None, None);
+        let rest = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let (name, rest) = bracketed.split_once(']')?;
+                thread = Some(name);
+                rest.trim_start()
+            }
+            None => match next_token(rest)? {
+                (id, rest) if is_request_id(id) => {
+                    request_id = Some(id.to_string());
+                    rest
+                }
+                _ => rest,
+            },
+        };
+        let (level, rest) = next_token(rest)?;
+        let severity = severity_from_level(level)?;
+        let (logger, rest) = next_token(rest)?;
+        let message = rest.strip_prefix('-').map_or(rest, str::trim_start);
+        let mut parsed = text_log(message, Some(timestamp), severity, logger, thread);
+        parsed.request_id = request_id;
+        Some(parsed)
+    }
+}
+
+/// Plain text that starts with a level: `[ERROR] ...`, `WARN: ...` or
+/// `INFO - ...`. Without brackets the level must be in capitals, so prose
+/// that starts with "Error" is left alone.
+pub struct LevelPrefixParser;
+
+impl LogParser for LevelPrefixParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let line = line.trim();
+        let (level, message) = match line.strip_prefix('[') {
+            Some(bracketed) => bracketed.split_once(']')?,
+            None => {
+                let end = line
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(line.len());
+                let (level, rest) = line.split_at(end);
+                if level != level.to_ascii_uppercase()
+                    || !(rest.is_empty() || rest.starts_with([':', ' ', '\t']))
+                {
+                    return None;
+                }
+                (level, rest)
+            }
+        };
+        let severity = severity_from_level(level)?;
+        let message = message.trim_start_matches([':', ' ', '\t', '-']);
+        Some(ParsedLog {
+            body: Some(AnyValue::from(message.to_string())),
+            severity: Some(severity),
+            ..Default::default()
+        })
+    }
+}
+
+/// Tries each format in turn, so lines in different formats can share a
+/// log stream.
+pub struct AutoParser;
+
+impl LogParser for AutoParser {
+    fn parse(&self, line: &str) -> Option<ParsedLog> {
+        let parsers: [&dyn LogParser; 6] = [
+            &JsonParser,
+            &LambdaParser,
+            &LogfmtParser,
+            &PythonParser,
+            &JavaParser,
+            &LevelPrefixParser,
+        ];
+        parsers.iter().find_map(|parser| parser.parse(line))
+    }
+}
+
+/// Takes the message, timestamp, level and request id out of structured
+/// fields and keeps the rest as attributes.
+fn from_fields(mut fields: Map<String, Value>) -> ParsedLog {
+    let mut parsed = ParsedLog::default();
+    if let Some(key) = MESSAGE_FIELDS
+        .iter()
+        .find(|key| fields.get(**key).is_some_and(Value::is_string))
+    {
+        parsed.body = fields.remove(*key).map(json_to_any_value);
+    }
+    for key in TIME_FIELDS {
+        let timestamp = fields.get(key).and_then(|value| match value {
+            Value::String(time) => parse_time(time),
+            Value::Number(time) => parse_time(&time.to_string()),
+            _ => None,
+        });
+        if timestamp.is_some() {
+            parsed.timestamp = timestamp;
+            fields.remove(key);
+            break;
+        }
+    }
+    for key in LEVEL_FIELDS {
+        let severity = match (key, fields.get(key)) {
+            (_, Some(Value::String(level))) => severity_from_level(level),
+            // pino and bunyan write numbers, which mean something else
+            // under the other names.
+            ("level", Some(Value::Number(level))) => level.as_i64().and_then(severity_from_number),
+            _ => None,
+        };
+        if severity.is_some() {
+            parsed.severity = severity;
+            fields.remove(key);
+            break;
+        }
+    }
+    // ECS nests it as `{"log": {"level": ...}}`.
+    if let (None, Some(Value::Object(log))) = (parsed.severity, fields.get_mut("log")) {
+        parsed.severity = log
+            .get("level")
+            .and_then(Value::as_str)
+            .and_then(severity_from_level);
+        if parsed.severity.is_some() {
+            log.remove("level");
+            if log.is_empty() {
+                fields.remove("log");
+            }
+        }
+    }
+    if let Some(key) = REQUEST_ID_FIELDS
+        .iter()
+        .find(|key| fields.get(**key).is_some_and(Value::is_string))
+    {
+        parsed.request_id = fields
+            .remove(*key)
+            .and_then(|value| value.as_str().map(str::to_string));
+    }
+    parsed.attributes = fields
+        .into_iter()
+        .map(|(key, value)| (Key::new(key), json_to_any_value(value)))
+        .collect();
+    parsed
+}
+
+fn text_log(
+    message: &str,
+    timestamp: Option<SystemTime>,
+    severity: Severity,
+    logger: &str,
+    thread: Option<&str>,
+) -> ParsedLog {
+    let mut attributes = vec![(
+        Key::new(semconv::CODE_NAMESPACE),
+        AnyValue::from(logger.to_string()),
+    )];
+    if let Some(thread) = thread {
+        attributes.push((
+            Key::new(semconv::THREAD_NAME),
+            AnyValue::from(thread.to_string()),
+        ));
+    }
+    ParsedLog {
+        body: Some(AnyValue::from(message.to_string())),
+        timestamp,
+        severity: Some(severity),
+        request_id: None,
+        attributes,
+    }
+}
+
+fn json_to_any_value(value: Value) -> AnyValue {
+    match value {
+        Value::String(s) => AnyValue::from(s),
+        Value::Bool(b) => AnyValue::from(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => AnyValue::from(i),
+            None => AnyValue::from(n.as_f64().unwrap_or_default()),
+        },
+        Value::Array(values) => values.into_iter().map(json_to_any_value).collect(),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| (Key::new(key), json_to_any_value(value)))
+            .collect(),
+        Value::Null => AnyValue::from(String::new()),
+    }
+}
+
+/// Maps the level names of common logging libraries, syslog and
+/// `java.util.logging` onto OpenTelemetry's severities.
+fn severity_from_level(level: &str) -> Option<Severity> {
+    let severity = match level.trim().to_ascii_uppercase().as_str() {
+        "TRACE" | "FINEST" | "FINER" | "VERBOSE" => Severity::Trace,
+        "DEBUG" | "FINE" => Severity::Debug,
+        "INFO" | "INFORMATION" | "CONFIG" => Severity::Info,
+        "NOTICE" => Severity::Info2,
+        "WARN" | "WARNING" => Severity::Warn,
+        "ERROR" | "ERR" | "SEVERE" => Severity::Error,
+        "FATAL" | "CRITICAL" | "CRIT" | "ALERT" | "EMERGENCY" | "EMERG" | "PANIC" => {
+            Severity::Fatal
+        }
+        _ => return None,
+    };
+    Some(severity)
+}
+
+// pino's and bunyan's numeric levels: 10 is trace, 20 debug, 30 info and so
+// on up to 60 for fatal.
+fn severity_from_number(level: i64) -> Option<Severity> {
+    let severity = match level {
+        ..=0 => return None,
+        1..=10 => Severity::Trace,
+        11..=20 => Severity::Debug,
+        21..=30 => Severity::Info,
+        31..=40 => Severity::Warn,
+        41..=50 => Severity::Error,
+        _ => Severity::Fatal,
+    };
+    Some(severity)
+}
+
+/// Reads RFC 3339, `2024-01-31 12:00:00.123` (or `,123`, as Python writes
+/// it) taken to be UTC, which is the Lambda default, or a Unix time in
+/// seconds, milliseconds, microseconds or nanoseconds.
+fn parse_time(time: &str) -> Option<SystemTime> {
+    let time = time.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.into());
+    }
+    let naive = time.replace(',', ".");
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(&naive, format) {
+            return Some(time.and_utc().into());
+        }
+    }
+    if let Ok(number) = time.parse::<u64>() {
+        let since_epoch = match number {
+            0 => return None,
+            n if n >= 100_000_000_000_000_000 => Duration::from_nanos(n),
+            n if n >= 100_000_000_000_000 => Duration::from_micros(n),
+            n if n >= 100_000_000_000 => Duration::from_millis(n),
+            n => Duration::from_secs(n),
+        };
+        return Some(UNIX_EPOCH + since_epoch);
+    }
+    // Fractional seconds, as Python's `time.time()` gives.
+    let seconds: f64 = time
+        .parse()
+        .ok()
+        .filter(|n: &f64| n.is_finite() && *n > 0.0 && *n < 1e11)?;
+    Some(UNIX_EPOCH + Duration::from_secs_f64(seconds))
+}
+
+// Lambda request ids are UUIDs.
+fn is_request_id(s: &str) -> bool {
+    s.len() == 36 && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+fn next_token(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+    Some(
+        s.split_once(char::is_whitespace)
+            .map_or((s, ""), |(token, rest)| (token, rest.trim_start())),
+    )
+}
+
+// Reads a double-quoted logfmt value whose opening quote has been consumed.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST_ID: &str = "8f5c1a2b-3c4d-4e5f-8a9b-0c1d2e3f4a5b";
+    // 2024-01-31T12:00:00Z
+    const NOON_MS: u64 = 1_706_702_400_000;
+
+    fn at(millis: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    fn body(parsed: &ParsedLog) -> Option<String> {
+        match &parsed.body {
+            Some(AnyValue::String(body)) => Some(body.to_string()),
+            _ => None,
+        }
+    }
+
+    fn at_nanos(nanos: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+
+    fn attribute(parsed: &ParsedLog, key: &str) -> Option<AnyValue> {
+        parsed
+            .attributes
+            .iter()
+            .find(|(k, _)| k.as_str() == key)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn text(value: &str) -> Option<AnyValue> {
+        Some(AnyValue::from(value.to_string()))
+    }
+
+    #[test]
+    fn json_takes_out_known_fields_and_keeps_the_rest() {
+        let parsed = JsonParser
+            .parse(&format!(
+                r#"{{"message":"hi","timestamp":"2024-01-31T12:00:00.123Z","requestId":"{}","user":"a","count":3,"ok":true}}"#,
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("hi"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert_eq!(attribute(&parsed, "user"), text("a"));
+        assert_eq!(attribute(&parsed, "count"), Some(AnyValue::from(3i64)));
+        assert_eq!(attribute(&parsed, "ok"), Some(AnyValue::from(true)));
+        assert_eq!(parsed.attributes.len(), 3);
+    }
+
+    #[test]
+    fn json_reads_alternative_field_names() {
+        let parsed = JsonParser
+            .parse(&format!(
+                r#"{{"msg":"hi","time":1706702400123,"AWSRequestId":"{}"}}"#,
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("hi"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert!(parsed.attributes.is_empty());
+    }
+
+    #[test]
+    fn json_keeps_a_structured_message_as_an_attribute() {
+        let parsed = JsonParser
+            .parse(r#"{"message":{"a":1},"time":"not a time"}"#)
+            .unwrap();
+        assert_eq!(parsed.body, None);
+        assert_eq!(parsed.timestamp, None);
+        assert!(attribute(&parsed, "message").is_some());
+        assert_eq!(attribute(&parsed, "time"), text("not a time"));
+    }
+
+    #[test]
+    fn json_only_accepts_objects() {
+        for line in ["[1, 2]", "\"text\"", "42", "{not json}", "plain text", ""] {
+            assert!(JsonParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn lambda_parses_the_node_prefix() {
+        let parsed = LambdaParser
+            .parse(&format!(
+                "2024-01-31T12:00:00.123Z\t{}\tINFO\tHello\tworld\n",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Hello\tworld"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert_eq!(parsed.severity, Some(Severity::Info));
+    }
+
+    #[test]
+    fn lambda_parses_the_python_prefix() {
+        let parsed = LambdaParser
+            .parse(&format!(
+                "[ERROR]\t2024-01-31T12:00:00.123Z\t{}\tSomething failed",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Something failed"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert_eq!(parsed.severity, Some(Severity::Error));
+    }
+
+    #[test]
+    fn lambda_parses_a_json_message() {
+        let parsed = LambdaParser
+            .parse(&format!(
+                "2024-01-31T12:00:00.123Z\t{}\tINFO\t{{\"message\":\"inner\",\"user\":\"a\"}}",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("inner"));
+        assert_eq!(attribute(&parsed, "user"), text("a"));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert_eq!(parsed.severity, Some(Severity::Info));
+    }
+
+    #[test]
+    fn lambda_needs_a_time_and_a_request_id_or_level() {
+        for line in [
+            "2024-01-31T12:00:00Z\tjust text",
+            "yesterday\tINFO\tHello",
+            "START RequestId: 8f5c1a2b Version: $LATEST",
+            "Hello world",
+        ] {
+            assert!(LambdaParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn logfmt_reads_quoted_and_bare_values() {
+        let parsed = LogfmtParser
+            .parse(r#"ts=2024-01-31T12:00:00Z msg="say \"hi\" to bob" user=alice duration=12ms"#)
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some(r#"say "hi" to bob"#));
+        assert_eq!(parsed.timestamp, at(NOON_MS));
+        assert_eq!(attribute(&parsed, "user"), text("alice"));
+        assert_eq!(attribute(&parsed, "duration"), text("12ms"));
+    }
+
+    #[test]
+    fn logfmt_rejects_prose_and_malformed_pairs() {
+        for line in [
+            "count=3",
+            "The value x=1 is odd",
+            "Retrying with attempts=3 backoff=2",
+            r#"a=1 msg="unterminated"#,
+            "a=1 =2",
+            "a=1 b",
+            "no pairs here",
+        ] {
+            assert!(LogfmtParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn python_parses_the_asctime_format() {
+        let parsed = PythonParser
+            .parse("2024-01-31 12:00:00,123 - app.db - WARNING - Slow query - 2s")
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Slow query - 2s"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.severity, Some(Severity::Warn));
+        assert_eq!(attribute(&parsed, semconv::CODE_NAMESPACE), text("app.db"));
+    }
+
+    #[test]
+    fn python_parses_the_basic_config_format() {
+        let parsed = PythonParser.parse("INFO:root:Started: ok").unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Started: ok"));
+        assert_eq!(parsed.timestamp, None);
+        assert_eq!(parsed.severity, Some(Severity::Info));
+        assert_eq!(attribute(&parsed, semconv::CODE_NAMESPACE), text("root"));
+    }
+
+    #[test]
+    fn python_rejects_lookalikes() {
+        for line in [
+            "Note:something:else",
+            "ERROR:my logger:x",
+            "http://example.com",
+            "TODO:fix:this",
+            "2024-01-31 12:00:00 - app - SOMETIMES - x",
+        ] {
+            assert!(PythonParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn java_parses_the_logback_layout() {
+        let parsed = JavaParser
+            .parse("2024-01-31 12:00:00.123 [main] INFO  com.example.App - Started in 2s")
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Started in 2s"));
+        assert_eq!(parsed.timestamp, at(NOON_MS + 123));
+        assert_eq!(parsed.severity, Some(Severity::Info));
+        assert_eq!(
+            attribute(&parsed, semconv::CODE_NAMESPACE),
+            text("com.example.App")
+        );
+        assert_eq!(attribute(&parsed, semconv::THREAD_NAME), text("main"));
+        assert_eq!(parsed.request_id, None);
+    }
+
+    #[test]
+    fn java_parses_the_lambda_log4j_layout() {
+        let parsed = JavaParser
+            .parse(&format!(
+                "2024-01-31 12:00:00.123 {} ERROR com.example.Handler - Failed",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Failed"));
+        assert_eq!(parsed.severity, Some(Severity::Error));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert_eq!(attribute(&parsed, semconv::THREAD_NAME), None);
+    }
+
+    #[test]
+    fn java_rejects_lines_without_a_known_level() {
+        for line in [
+            "2024-01-31 12:00:00.123 [main] LOUD com.example.App - x",
+            "2024-01-31 12:00:00.123 [main",
+            "2024-01-31 12:00:00.123",
+            "Jan 31 12:00:00 [main] INFO App - x",
+        ] {
+            assert!(JavaParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn level_prefix_reads_the_level_and_message() {
+        let parsed = LevelPrefixParser.parse("[ERROR] Something broke").unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("Something broke"));
+        assert_eq!(parsed.severity, Some(Severity::Error));
+
+        let parsed = LevelPrefixParser.parse("INFO - ready").unwrap();
+        assert_eq!(body(&parsed).as_deref(), Some("ready"));
+        assert_eq!(parsed.severity, Some(Severity::Info));
+    }
+
+    #[test]
+    fn level_prefix_leaves_prose_alone() {
+        for line in [
+            "Error reading file",
+            "Warning: this is prose",
+            "[2024] happened",
+            "ERRORS: 3",
+            "Hello",
+        ] {
+            assert!(LevelPrefixParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn auto_tells_the_formats_apart() {
+        let json = AutoParser.parse(r#"{"msg":"from json","a":1}"#).unwrap();
+        assert_eq!(body(&json).as_deref(), Some("from json"));
+
+        let node = AutoParser
+            .parse(&format!(
+                "2024-01-31T12:00:00.123Z\t{}\tINFO\tfrom node",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&node).as_deref(), Some("from node"));
+        assert_eq!(node.request_id.as_deref(), Some(REQUEST_ID));
+
+        let python = AutoParser
+            .parse(&format!(
+                "[ERROR]\t2024-01-31T12:00:00.123Z\t{}\tfrom python",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(body(&python).as_deref(), Some("from python"));
+        assert_eq!(python.request_id.as_deref(), Some(REQUEST_ID));
+
+        let logfmt = AutoParser.parse(r#"msg="from logfmt" user=alice"#).unwrap();
+        assert_eq!(body(&logfmt).as_deref(), Some("from logfmt"));
+        assert_eq!(attribute(&logfmt, "user"), text("alice"));
+
+        let logging = AutoParser
+            .parse("2024-01-31 12:00:00,123 - app - INFO - from logging")
+            .unwrap();
+        assert_eq!(body(&logging).as_deref(), Some("from logging"));
+        assert_eq!(attribute(&logging, semconv::CODE_NAMESPACE), text("app"));
+
+        let logback = AutoParser
+            .parse("2024-01-31 12:00:00.123 [main] WARN com.example.App - from logback")
+            .unwrap();
+        assert_eq!(body(&logback).as_deref(), Some("from logback"));
+        assert_eq!(attribute(&logback, semconv::THREAD_NAME), text("main"));
+
+        let prefixed = AutoParser.parse("WARN: from a prefix").unwrap();
+        assert_eq!(body(&prefixed).as_deref(), Some("from a prefix"));
+        assert!(prefixed.attributes.is_empty());
+    }
+
+    #[test]
+    fn auto_leaves_prose_unparsed() {
+        for line in [
+            "Hello, world",
+            "The value x=1 is odd",
+            "a=b",
+            "Processing order 42: done",
+            "START RequestId: 8f5c1a2b Version: $LATEST",
+            "42",
+            "",
+        ] {
+            assert!(AutoParser.parse(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("2024-01-31T12:00:00Z"), at(NOON_MS));
+        assert_eq!(parse_time("2024-01-31T13:00:00.5+01:00"), at(NOON_MS + 500));
+        assert_eq!(parse_time("2024-01-31 12:00:00,250"), at(NOON_MS + 250));
+        assert_eq!(parse_time("2024-01-31T12:00:00.250"), at(NOON_MS + 250));
+        assert_eq!(parse_time(" 1706702400 "), at(NOON_MS));
+        assert_eq!(parse_time("1706702400123"), at(NOON_MS + 123));
+        assert_eq!(
+            parse_time("1706702400123456"),
+            at_nanos(NOON_MS * 1_000_000 + 123_456_000)
+        );
+        assert_eq!(
+            parse_time("1706702400123456789"),
+            at_nanos(NOON_MS * 1_000_000 + 123_456_789)
+        );
+        assert_eq!(parse_time("1706702400.5"), at(NOON_MS + 500));
+        for time in ["0", "-5", "NaN", "inf", "noon", "2024-13-01T00:00:00Z", ""] {
+            assert_eq!(parse_time(time), None, "{:?}", time);
+        }
+    }
+
+    #[test]
+    fn unquotes_logfmt_values() {
+        assert_eq!(unquote(r#"a b" rest"#), Some(("a b".to_string(), " rest")));
+        assert_eq!(unquote(r#"a\"b\\c""#), Some((r#"a"b\c"#.to_string(), "")));
+        assert_eq!(unquote("unterminated"), None);
+        assert_eq!(unquote(r#"trailing\"#), None);
+    }
+
+    #[test]
+    fn reads_the_level_from_any_level_field() {
+        for (line, severity) in [
+            (r#"{"level":"warn","msg":"a"}"#, Severity::Warn),
+            (r#"{"severity":"ERROR","msg":"a"}"#, Severity::Error),
+            (r#"{"levelname":"DEBUG","msg":"a"}"#, Severity::Debug),
+            (r#"{"log.level":"info","msg":"a"}"#, Severity::Info),
+        ] {
+            let parsed = JsonParser.parse(line).unwrap();
+            assert_eq!(parsed.severity, Some(severity), "{}", line);
+            assert!(parsed.attributes.is_empty(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn reads_the_nested_ecs_level() {
+        let parsed = JsonParser
+            .parse(r#"{"message":"a","log":{"level":"warn","logger":"app"}}"#)
+            .unwrap();
+        assert_eq!(parsed.severity, Some(Severity::Warn));
+        let log = attribute(&parsed, "log").unwrap();
+        let AnyValue::Map(log) = log else {
+            panic!("log is not a map: {:?}", log);
+        };
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.get(&Key::new("logger")), text("app").as_ref());
+
+        let parsed = JsonParser
+            .parse(r#"{"message":"a","log":{"level":"error"}}"#)
+            .unwrap();
+        assert_eq!(parsed.severity, Some(Severity::Error));
+        assert!(parsed.attributes.is_empty());
+    }
+
+    #[test]
+    fn reads_pino_numeric_levels() {
+        for (level, severity) in [
+            (10, Severity::Trace),
+            (20, Severity::Debug),
+            (30, Severity::Info),
+            (35, Severity::Warn),
+            (40, Severity::Warn),
+            (50, Severity::Error),
+            (60, Severity::Fatal),
+        ] {
+            let parsed = JsonParser
+                .parse(&format!(r#"{{"level":{},"msg":"a"}}"#, level))
+                .unwrap();
+            assert_eq!(parsed.severity, Some(severity), "{}", level);
+        }
+        assert_eq!(severity_from_number(0), None);
+        assert_eq!(severity_from_number(-10), None);
+        assert_eq!(severity_from_number(100), Some(Severity::Fatal));
+    }
+
+    #[test]
+    fn only_reads_numbers_from_the_level_field() {
+        let parsed = JsonParser.parse(r#"{"severity":50,"msg":"a"}"#).unwrap();
+        assert_eq!(parsed.severity, None);
+        assert_eq!(attribute(&parsed, "severity"), Some(AnyValue::from(50i64)));
+    }
+
+    #[test]
+    fn keeps_unknown_levels_as_attributes() {
+        let parsed = JsonParser.parse(r#"{"level":"loud","msg":"a"}"#).unwrap();
+        assert_eq!(parsed.severity, None);
+        assert_eq!(attribute(&parsed, "level"), text("loud"));
+    }
+
+    #[test]
+    fn reads_lambdas_json_log_format() {
+        let parsed = AutoParser
+            .parse(&format!(
+                r#"{{"timestamp":"2024-01-31T12:00:00.123Z","level":"ERROR","requestId":"{}","message":"boom"}}"#,
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(parsed.severity, Some(Severity::Error));
+        assert_eq!(body(&parsed).as_deref(), Some("boom"));
+        assert_eq!(parsed.request_id.as_deref(), Some(REQUEST_ID));
+        assert!(parsed.attributes.is_empty());
+    }
+
+    #[test]
+    fn a_json_message_level_beats_the_runtime_prefix() {
+        let parsed = LambdaParser
+            .parse(&format!(
+                "2024-01-31T12:00:00.123Z\t{}\tINFO\t{{\"level\":\"error\",\"msg\":\"a\"}}",
+                REQUEST_ID
+            ))
+            .unwrap();
+        assert_eq!(parsed.severity, Some(Severity::Error));
+    }
+
+    #[test]
+    fn reads_level_prefixes() {
+        for (line, severity) in [
+            ("[ERROR] a", Severity::Error),
+            ("[error] a", Severity::Error),
+            ("[WARNING]a", Severity::Warn),
+            ("WARN: a", Severity::Warn),
+            ("DEBUG a", Severity::Debug),
+            ("FATAL", Severity::Fatal),
+            ("\tNOTICE - a", Severity::Info2),
+        ] {
+            let parsed = AutoParser.parse(line).unwrap();
+            assert_eq!(parsed.severity, Some(severity), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn maps_level_names() {
+        for (level, severity) in [
+            ("trace", Severity::Trace),
+            ("FINEST", Severity::Trace),
+            ("finer", Severity::Trace),
+            ("verbose", Severity::Trace),
+            ("Debug", Severity::Debug),
+            ("FINE", Severity::Debug),
+            ("info", Severity::Info),
+            ("INFORMATION", Severity::Info),
+            ("CONFIG", Severity::Info),
+            ("notice", Severity::Info2),
+            ("warn", Severity::Warn),
+            ("Warning", Severity::Warn),
+            ("error", Severity::Error),
+            ("ERR", Severity::Error),
+            ("SEVERE", Severity::Error),
+            ("fatal", Severity::Fatal),
+            ("CRITICAL", Severity::Fatal),
+            ("crit", Severity::Fatal),
+            ("alert", Severity::Fatal),
+            ("EMERGENCY", Severity::Fatal),
+            ("emerg", Severity::Fatal),
+            ("panic", Severity::Fatal),
+            (" info ", Severity::Info),
+        ] {
+            assert_eq!(severity_from_level(level), Some(severity), "{:?}", level);
+        }
+        for level in ["", "loud", "30", "INFOS", "warn!"] {
+            assert_eq!(severity_from_level(level), None, "{:?}", level);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! One export pipeline per destination and signal. The SDK providers flush
+//! their processors one after another, so each destination's processor is
+//! also kept here and flushed on its own, letting a slow or failing
+//! destination time out without holding up the others.
+
+use crate::flush::Signal;
+use crate::receiver::ForwardedMetrics;
+use crate::retry;
+use crate::secrets;
+use crate::spill::Spool;
+use opentelemetry::global;
+use opentelemetry::logs::LogResult;
+use opentelemetry::trace::TraceResult;
+use opentelemetry::InstrumentationLibrary;
+use opentelemetry_proto::tonic::metrics::v1::ResourceMetrics as ReceivedMetrics;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::logs::{BatchLogProcessor, LogProcessor, LogRecord};
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, MetricReader, TemporalitySelector};
+use opentelemetry_sdk::metrics::{
+    Aggregation, InstrumentKind, PeriodicReader, Pipeline as MetricPipeline,
+};
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::{BatchSpanProcessor, Span, SpanProcessor};
+use opentelemetry_sdk::Resource;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// A processor or reader shared between a provider and its [`Pipeline`].
+/// Shutting it down twice is a no-op, so the provider shutting it down again
+/// when it is dropped does not report an error.
+#[derive(Debug)]
+pub struct Shared<T> {
+    inner: Arc<(T, AtomicBool)>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(inner: T) -> Self {
+        Shared {
+            inner: Arc::new((inner, AtomicBool::new(false))),
+        }
+    }
+
+    fn get(&self) -> &T {
+        &self.inner.0
+    }
+
+    // Whether this is the first call, after which the inner value is shut down.
+    fn first_shutdown(&self) -> bool {
+        !self.inner.1.swap(true, Ordering::SeqCst)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl SpanProcessor for Shared<BatchSpanProcessor<Tokio>> {
+    fn on_start(&self, span: &mut Span, cx: &opentelemetry::Context) {
+        self.get().on_start(span, cx)
+    }
+
+    fn on_end(&self, span: SpanData) {
+        self.get().on_end(span)
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.get().force_flush()
+    }
+
+    fn shutdown(&self) -> TraceResult<()> {
+        if self.first_shutdown() {
+            self.get().shutdown()
+        } else {
+            Ok(())
+        }
+    }
+
+    // The resource is given to the exporter before the processor is built,
+    // since a shared processor cannot be borrowed mutably.
+    fn set_resource(&mut self, _resource: &Resource) {}
+}
+
+impl LogProcessor for Shared<BatchLogProcessor<Tokio>> {
+    fn emit(&self, data: &mut LogRecord, instrumentation: &InstrumentationLibrary) {
+        self.get().emit(data, instrumentation)
+    }
+
+    fn force_flush(&self) -> LogResult<()> {
+        self.get().force_flush()
+    }
+
+    fn shutdown(&self) -> LogResult<()> {
+        if self.first_shutdown() {
+            self.get().shutdown()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_resource(&self, resource: &Resource) {
+        self.get().set_resource(resource)
+    }
+}
+
+impl TemporalitySelector for Shared<PeriodicReader> {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.get().temporality(kind)
+    }
+}
+
+impl AggregationSelector for Shared<PeriodicReader> {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.get().aggregation(kind)
+    }
+}
+
+impl MetricReader for Shared<PeriodicReader> {
+    fn register_pipeline(&self, pipeline: Weak<MetricPipeline>) {
+        self.get().register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        self.get().collect(rm)
+    }
+
+    fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        self.get().force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        if self.first_shutdown() {
+            self.get().shutdown()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Processor {
+    Spans(Shared<BatchSpanProcessor<Tokio>>),
+    Metrics(Shared<PeriodicReader>, ForwardedMetrics),
+    Logs(Shared<BatchLogProcessor<Tokio>>),
+}
+
+/// One signal's export to one destination.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    destination: String,
+    processor: Processor,
+    spool: Option<Arc<Spool>>,
+}
+
+impl Pipeline {
+    pub fn spans(destination: &str, processor: Shared<BatchSpanProcessor<Tokio>>) -> Self {
+        Pipeline {
+            destination: destination.to_string(),
+            processor: Processor::Spans(processor),
+            spool: None,
+        }
+    }
+
+    pub fn metrics(
+        destination: &str,
+        reader: Shared<PeriodicReader>,
+        forwarded: ForwardedMetrics,
+    ) -> Self {
+        Pipeline {
+            destination: destination.to_string(),
+            processor: Processor::Metrics(reader, forwarded),
+            spool: None,
+        }
+    }
+
+    pub fn logs(destination: &str, processor: Shared<BatchLogProcessor<Tokio>>) -> Self {
+        Pipeline {
+            destination: destination.to_string(),
+            processor: Processor::Logs(processor),
+            spool: None,
+        }
+    }
+
+    /// Where this pipeline's exporter spills what it could not send.
+    pub fn with_spool(mut self, spool: Option<Arc<Spool>>) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    pub fn signal(&self) -> Signal {
+        match self.processor {
+            Processor::Spans(_) => Signal::Traces,
+            Processor::Metrics(..) => Signal::Metrics,
+            Processor::Logs(_) => Signal::Logs,
+        }
+    }
+
+    fn run(&self, action: Action) -> Result<(), global::Error> {
+        match (action, &self.processor) {
+            (Action::Flush, Processor::Spans(p)) => p.force_flush().map_err(Into::into),
+            (Action::Flush, Processor::Metrics(r, _)) => r.force_flush().map_err(Into::into),
+            (Action::Flush, Processor::Logs(p)) => p.force_flush().map_err(Into::into),
+            (Action::Shutdown, Processor::Spans(p)) => p.shutdown().map_err(Into::into),
+            (Action::Shutdown, Processor::Metrics(r, _)) => r.shutdown().map_err(Into::into),
+            (Action::Shutdown, Processor::Logs(p)) => p.shutdown().map_err(Into::into),
+        }
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} to {}", self.signal(), self.destination)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Flush,
+    Shutdown,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Flush => "flush",
+            Action::Shutdown => "shutdown",
+        })
+    }
+}
+
+/// Every pipeline, so they can be flushed together.
+#[derive(Clone, Debug, Default)]
+pub struct Pipelines(pub Vec<Pipeline>);
+
+impl Pipelines {
+    /// Exports everything the batch processors are holding, giving up after
+    /// `budget`. Returns the pipelines that were not fully exported.
+    pub async fn force_flush(&self, budget: Duration) -> Vec<Pipeline> {
+        self.each(Action::Flush, budget).await
+    }
+
+    /// Exports what is left and shuts every pipeline down, giving up after
+    /// `budget`. Returns the pipelines that were not fully exported.
+    pub async fn shutdown(&self, budget: Duration) -> Vec<Pipeline> {
+        self.each(Action::Shutdown, budget).await
+    }
+
+    /// Sends what was spilled by an earlier instance of the extension in
+    /// this execution environment.
+    pub async fn replay(&self) {
+        for spool in self.0.iter().filter_map(|pipeline| pipeline.spool.as_ref()) {
+            spool.replay().await;
+        }
+    }
+
+    /// Hands spans received from the function to every trace pipeline.
+    pub fn forward_spans(&self, spans: Vec<SpanData>) {
+        for pipeline in &self.0 {
+            if let Processor::Spans(p) = &pipeline.processor {
+                for span in &spans {
+                    p.on_end(span.clone());
+                }
+            }
+        }
+    }
+
+    /// Hands metrics received from the function to every metrics pipeline,
+    /// to go out with its next export.
+    pub fn forward_metrics(&self, metrics: Vec<ReceivedMetrics>) {
+        for pipeline in &self.0 {
+            if let Processor::Metrics(_, forwarded) = &pipeline.processor {
+                forwarded.push(metrics.clone());
+            }
+        }
+    }
+
+    /// Hands log records received from the function to every log pipeline.
+    pub fn forward_logs(&self, records: Vec<(LogRecord, InstrumentationLibrary)>) {
+        for pipeline in &self.0 {
+            if let Processor::Logs(p) = &pipeline.processor {
+                for (record, library) in &records {
+                    p.emit(&mut record.clone(), library);
+                }
+            }
+        }
+    }
+
+    // The SDK blocks until the exporters return, so each pipeline runs on
+    // its own blocking task and a slow one cannot hold up the others.
+    async fn each(&self, action: Action, budget: Duration) -> Vec<Pipeline> {
+        let deadline = tokio::time::Instant::now() + budget;
+        retry::set_deadline(Some(deadline.into_std()));
+        let tasks: Vec<_> = self
+            .0
+            .iter()
+            .map(|pipeline| {
+                let running = pipeline.clone();
+                let task = tokio::task::spawn_blocking(move || running.run(action));
+                (pipeline, task)
+            })
+            .collect();
+
+        let mut failed = Vec::new();
+        for (pipeline, task) in tasks {
+            match tokio::time::timeout_at(deadline, task).await {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => {
+                    error!("Failed to {} {}: {}", action, pipeline, e);
+                    secrets::on_export_error(&e);
+                    failed.push(pipeline.clone());
+                }
+                Ok(Err(e)) => {
+                    error!("The {} task for {} failed: {:?}", action, pipeline, e);
+                    failed.push(pipeline.clone());
+                }
+                Err(_) => {
+                    warn!(
+                        "The {} of {} did not finish within {:?}",
+                        action, pipeline, budget
+                    );
+                    failed.push(pipeline.clone());
+                }
+            }
+        }
+        retry::set_deadline(None);
+        failed
+    }
+}
@@ -0,0 +1,730 @@
+//! A local OTLP receiver, so function code can export to the extension
+//! instead of a remote collector and not wait on the network during the
+//! invocation. Requests are acknowledged once decoded; what they carry
+//! joins the extension's own telemetry in the export pipelines.
+
+use crate::invocation::InvocationTracker;
+use crate::logs::LogLevel;
+use crate::otlp;
+use crate::pipeline::Pipelines;
+use crate::resource;
+use crate::semconv;
+use crate::stitch::{Stitcher, Stitching};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use flate2::read::GzDecoder;
+use opentelemetry::logs::Severity;
+use opentelemetry::KeyValue;
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::{
+    LogsService, LogsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::logs::v1::{
+    ExportLogsServiceRequest, ExportLogsServiceResponse,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+    MetricsService, MetricsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use opentelemetry_proto::tonic::metrics::v1::ResourceMetrics as ReceivedMetrics;
+use opentelemetry_proto::tonic::resource::v1::Resource as ProtoResource;
+use opentelemetry_sdk::logs::TraceContext;
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, TemporalitySelector};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind};
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tracing::{debug, error, info, warn};
+
+// Metric requests held for one destination between its exports.
+const MAX_FORWARDED_METRICS: usize = 256;
+
+/// Where the function can export to. Both protocols are off unless given an
+/// address.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReceiverSettings {
+    /// OTLP/HTTP, usually `127.0.0.1:4318`.
+    pub http: Option<SocketAddr>,
+    /// OTLP/gRPC, usually `127.0.0.1:4317`.
+    pub grpc: Option<SocketAddr>,
+    /// The largest request accepted, once decompressed.
+    pub max_request_bytes: usize,
+    /// How the function's spans are joined to the invocation span.
+    pub stitching: Stitching,
+}
+
+impl Default for ReceiverSettings {
+    fn default() -> Self {
+        ReceiverSettings {
+            http: None,
+            grpc: None,
+            max_request_bytes: 8 * 1024 * 1024,
+            stitching: Stitching::default(),
+        }
+    }
+}
+
+impl ReceiverSettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.max_request_bytes == 0 {
+            problems.push("receiver.max_request_bytes must be greater than 0".to_string());
+        }
+        if self.http.is_some() && self.http == self.grpc {
+            problems
+                .push("receiver.http and receiver.grpc must use different addresses".to_string());
+        }
+        problems
+    }
+
+    /// Whether an exporter sending to `host` and `port` would reach this
+    /// receiver, and so send the function's telemetry back to itself.
+    pub fn receives(&self, host: &str, port: u16) -> bool {
+        let local = |host: &str| {
+            host.eq_ignore_ascii_case("localhost")
+                || host
+                    .trim_matches(['[', ']'])
+                    .parse::<std::net::IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified())
+        };
+        [self.http, self.grpc].into_iter().flatten().any(|address| {
+            address.port() == port
+                && local(host)
+                && (address.ip().is_loopback() || address.ip().is_unspecified())
+        })
+    }
+}
+
+/// Hands what the function exports to the export pipelines, tagged with
+/// and stitched to the invocation it was produced in.
+#[derive(Clone)]
+pub struct Receiver {
+    settings: ReceiverSettings,
+    pipelines: Pipelines,
+    tracker: Arc<Mutex<InvocationTracker>>,
+    stitcher: Arc<Mutex<Stitcher>>,
+    adopt_service_name: bool,
+    min_severity: Option<Severity>,
+}
+
+impl Receiver {
+    pub fn new(
+        settings: ReceiverSettings,
+        pipelines: Pipelines,
+        tracker: Arc<Mutex<InvocationTracker>>,
+    ) -> Self {
+        Receiver {
+            settings,
+            pipelines,
+            tracker,
+            stitcher: Arc::new(Mutex::new(Stitcher::new(settings.stitching))),
+            adopt_service_name: false,
+            min_severity: None,
+        }
+    }
+
+    /// Whether the service name the function's SDK reports replaces the
+    /// extension's, for when none is configured.
+    pub fn with_service_name_adopted(mut self, adopt: bool) -> Self {
+        self.adopt_service_name = adopt;
+        self
+    }
+
+    /// Drops log records less severe than `min_level`, as the Telemetry API
+    /// lines are. Records without a severity are kept.
+    pub fn with_min_level(mut self, min_level: Option<LogLevel>) -> Self {
+        self.min_severity = min_level.map(LogLevel::severity);
+        self
+    }
+
+    /// Listens on the configured addresses. Binding happens before this
+    /// returns, so an address in use is reported during INIT.
+    pub async fn start(&self) -> io::Result<()> {
+        let limit = self.settings.max_request_bytes;
+        if let Some(address) = self.settings.http {
+            let listener = TcpListener::bind(address).await?;
+            let app = Router::new()
+                .route("/v1/traces", post(http_traces))
+                .route("/v1/metrics", post(http_metrics))
+                .route("/v1/logs", post(http_logs))
+                .layer(DefaultBodyLimit::max(limit))
+                .with_state(self.clone());
+            info!("Receiving OTLP/HTTP on {}", address);
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!("The OTLP/HTTP receiver stopped: {}", e);
+                }
+            });
+        }
+        if let Some(address) = self.settings.grpc {
+            let listener = TcpListener::bind(address).await?;
+            let incoming =
+                TcpIncoming::from_listener(listener, true, None).map_err(io::Error::other)?;
+            let server = Server::builder()
+                .add_service(
+                    TraceServiceServer::new(self.clone())
+                        .accept_compressed(CompressionEncoding::Gzip)
+                        .accept_compressed(CompressionEncoding::Zstd)
+                        .max_decoding_message_size(limit),
+                )
+                .add_service(
+                    MetricsServiceServer::new(self.clone())
+                        .accept_compressed(CompressionEncoding::Gzip)
+                        .accept_compressed(CompressionEncoding::Zstd)
+                        .max_decoding_message_size(limit),
+                )
+                .add_service(
+                    LogsServiceServer::new(self.clone())
+                        .accept_compressed(CompressionEncoding::Gzip)
+                        .accept_compressed(CompressionEncoding::Zstd)
+                        .max_decoding_message_size(limit),
+                );
+            info!("Receiving OTLP/gRPC on {}", address);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_with_incoming(incoming).await {
+                    error!("The OTLP/gRPC receiver stopped: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn adopt_service_name<'a>(&self, resources: impl Iterator<Item = Option<&'a ProtoResource>>) {
+        if !self.adopt_service_name {
+            return;
+        }
+        if let Some(name) = resources.flatten().find_map(otlp::service_name) {
+            resource::adopt_service_name(&name);
+        }
+    }
+
+    fn receive_spans(&self, request: ExportTraceServiceRequest) {
+        self.adopt_service_name(request.resource_spans.iter().map(|r| r.resource.as_ref()));
+        let mut spans = otlp::spans(request.resource_spans);
+        let tracker = self.tracker.lock().unwrap();
+        let correlations: Vec<_> = spans
+            .iter_mut()
+            .map(|span| {
+                let request_id = span
+                    .attributes
+                    .iter()
+                    .find(|kv| kv.key.as_str() == semconv::FAAS_INVOCATION_ID)
+                    .map(|kv| kv.value.as_str().into_owned());
+                let correlation = tracker.invocation_for(
+                    request_id.as_deref(),
+                    Some(span.span_context.trace_id()),
+                    span.start_time,
+                );
+                if let (None, Some(request_id)) = (
+                    request_id,
+                    correlation.as_ref().and_then(|c| c.request_id.clone()),
+                ) {
+                    span.attributes
+                        .push(KeyValue::new(semconv::FAAS_INVOCATION_ID, request_id));
+                }
+                correlation
+            })
+            .collect();
+        drop(tracker);
+        self.stitcher
+            .lock()
+            .unwrap()
+            .stitch(&mut spans, &correlations);
+        debug!("Received {} span(s) from the function", spans.len());
+        self.pipelines.forward_spans(spans);
+    }
+
+    fn receive_metrics(&self, request: ExportMetricsServiceRequest) {
+        debug!("Received metrics from the function");
+        self.adopt_service_name(request.resource_metrics.iter().map(|r| r.resource.as_ref()));
+        self.pipelines.forward_metrics(request.resource_metrics);
+    }
+
+    // Records are correlated as the lines the function writes to stdout are,
+    // and follow their trace if it was moved into an invocation's.
+    fn receive_logs(&self, request: ExportLogsServiceRequest) {
+        use opentelemetry::logs::LogRecord as _;
+        self.adopt_service_name(request.resource_logs.iter().map(|r| r.resource.as_ref()));
+        let mut records = otlp::log_records(request.resource_logs);
+        if let Some(min_severity) = self.min_severity {
+            records.retain(|(record, _)| record.severity_number.is_none_or(|s| s >= min_severity));
+        }
+        {
+            let tracker = self.tracker.lock().unwrap();
+            let stitcher = self.stitcher.lock().unwrap();
+            for (record, _) in &mut records {
+                if let Some(trace_context) = record.trace_context.as_mut() {
+                    stitcher.stitch_log(trace_context);
+                }
+                let time = record
+                    .timestamp
+                    .or(record.observed_timestamp)
+                    .unwrap_or_else(SystemTime::now);
+                let trace_id = record.trace_context.as_ref().map(|cx| cx.trace_id);
+                let Some(correlation) = tracker.invocation_for(None, trace_id, time) else {
+                    continue;
+                };
+                if let Some(request_id) = correlation.request_id {
+                    record.add_attribute(semconv::FAAS_INVOCATION_ID, request_id);
+                }
+                if record.trace_context.is_none() {
+                    record.trace_context = Some(TraceContext::from(&correlation.span_context));
+                }
+            }
+        }
+        debug!("Received {} log record(s) from the function", records.len());
+        self.pipelines.forward_logs(records);
+    }
+}
+
+async fn http_traces(
+    State(receiver): State<Receiver>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    receive_http(&receiver, &headers, &body, |request| {
+        receiver.receive_spans(request)
+    })
+}
+
+async fn http_metrics(
+    State(receiver): State<Receiver>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    receive_http(&receiver, &headers, &body, |request| {
+        receiver.receive_metrics(request)
+    })
+}
+
+async fn http_logs(State(receiver): State<Receiver>, headers: HeaderMap, body: Bytes) -> Response {
+    receive_http(&receiver, &headers, &body, |request| {
+        receiver.receive_logs(request)
+    })
+}
+
+// Decodes an OTLP/HTTP request in either encoding and answers in the same
+// one. Every response is empty, meaning everything was accepted, which
+// encodes to no bytes at all in protobuf.
+fn receive_http<Req>(
+    receiver: &Receiver,
+    headers: &HeaderMap,
+    body: &[u8],
+    receive: impl FnOnce(Req),
+) -> Response
+where
+    Req: Message + Default + DeserializeOwned,
+{
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let json = match header(CONTENT_TYPE) {
+        Some(content_type) if content_type.starts_with("application/x-protobuf") => false,
+        Some(content_type) if content_type.starts_with("application/json") => true,
+        other => {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content type {:?}", other.unwrap_or_default()),
+            )
+                .into_response()
+        }
+    };
+    let body = match decompress(
+        header(CONTENT_ENCODING),
+        body,
+        receiver.settings.max_request_bytes,
+    ) {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let request = if json {
+        serde_json::from_slice::<Req>(&body).map_err(|e| e.to_string())
+    } else {
+        Req::decode(body.as_ref()).map_err(|e| e.to_string())
+    };
+    match request {
+        Ok(request) => receive(request),
+        Err(e) => {
+            warn!("Rejected an OTLP request from the function: {}", e);
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    }
+    if json {
+        ([(CONTENT_TYPE, "application/json")], "{}").into_response()
+    } else {
+        ([(CONTENT_TYPE, "application/x-protobuf")], "").into_response()
+    }
+}
+
+fn decompress<'a>(
+    encoding: Option<&str>,
+    body: &'a [u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, String> {
+    let reader: Box<dyn Read> = match encoding {
+        None | Some("identity") => return Ok(Cow::Borrowed(body)),
+        Some("gzip") => Box::new(GzDecoder::new(body)),
+        Some("zstd") => Box::new(zstd::Decoder::new(body).map_err(|e| e.to_string())?),
+        Some(other) => return Err(format!("unsupported content encoding {:?}", other)),
+    };
+    let mut decompressed = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| e.to_string())?;
+    if decompressed.len() > limit {
+        return Err(format!("the request is larger than {} bytes", limit));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+#[async_trait]
+impl TraceService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        self.receive_spans(request.into_inner());
+        Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+#[async_trait]
+impl MetricsService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.receive_metrics(request.into_inner());
+        Ok(tonic::Response::new(ExportMetricsServiceResponse::default()))
+    }
+}
+
+#[async_trait]
+impl LogsService for Receiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportLogsServiceRequest>,
+    ) -> Result<tonic::Response<ExportLogsServiceResponse>, tonic::Status> {
+        self.receive_logs(request.into_inner());
+        Ok(tonic::Response::new(ExportLogsServiceResponse::default()))
+    }
+}
+
+/// Metrics received from the function that one destination has yet to
+/// export. Metrics cannot be fed through the SDK's aggregation, so they are
+/// added to the destination's next export of the extension's own.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardedMetrics(Arc<Mutex<Vec<ReceivedMetrics>>>);
+
+impl ForwardedMetrics {
+    pub fn push(&self, metrics: Vec<ReceivedMetrics>) {
+        let mut forwarded = self.0.lock().unwrap();
+        forwarded.extend(metrics);
+        if forwarded.len() > MAX_FORWARDED_METRICS {
+            let excess = forwarded.len() - MAX_FORWARDED_METRICS;
+            forwarded.drain(..excess);
+            warn!(
+                "Dropped {} metric batch(es) from the function waiting to be exported",
+                excess
+            );
+        }
+    }
+
+    fn take(&self) -> Vec<ReceivedMetrics> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Adds the function's metrics to each export of the extension's own.
+#[derive(Debug)]
+pub struct ForwardingMetricsExporter<E> {
+    inner: E,
+    forwarded: ForwardedMetrics,
+}
+
+impl<E> ForwardingMetricsExporter<E> {
+    pub fn new(inner: E, forwarded: ForwardedMetrics) -> Self {
+        ForwardingMetricsExporter { inner, forwarded }
+    }
+}
+
+impl<E: TemporalitySelector> TemporalitySelector for ForwardingMetricsExporter<E> {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.inner.temporality(kind)
+    }
+}
+
+impl<E: AggregationSelector> AggregationSelector for ForwardingMetricsExporter<E> {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.inner.aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl<E: PushMetricsExporter> PushMetricsExporter for ForwardingMetricsExporter<E> {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        if let Some(resource) = resource::with_function_service_name(&metrics.resource) {
+            metrics.resource = resource;
+        }
+        let received = otlp::scope_metrics(self.forwarded.take());
+        if received.is_empty() {
+            return self.inner.export(metrics).await;
+        }
+        // The reader reuses `metrics`, so the received ones are removed
+        // again once sent.
+        let own = metrics.scope_metrics.len();
+        metrics.scope_metrics.extend(received);
+        let result = self.inner.export(metrics).await;
+        metrics.scope_metrics.truncate(own);
+        result
+    }
+
+    async fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        self.inner.force_flush().await
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Pipeline, Shared};
+    use axum::http::HeaderValue;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use opentelemetry::logs::LogResult;
+    use opentelemetry_proto::tonic::logs::v1::{LogRecord as ProtoRecord, ResourceLogs, ScopeLogs};
+    use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans, Span};
+    use opentelemetry_sdk::export::logs::{LogBatch, LogExporter};
+    use opentelemetry_sdk::logs::{BatchLogProcessor, LogProcessor, LogRecord};
+    use opentelemetry_sdk::runtime::Tokio;
+    use std::io::Write;
+
+    fn receiver(max_request_bytes: usize) -> Receiver {
+        let settings = ReceiverSettings {
+            max_request_bytes,
+            ..ReceiverSettings::default()
+        };
+        let tracer = opentelemetry::global::tracer("test");
+        let tracker = Arc::new(Mutex::new(InvocationTracker::new(tracer)));
+        Receiver::new(settings, Pipelines::default(), tracker)
+    }
+
+    fn request() -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                scope_spans: vec![ScopeSpans {
+                    spans: vec![Span {
+                        trace_id: vec![1; 16],
+                        span_id: vec![2; 8],
+                        name: "handler".to_string(),
+                        ..Span::default()
+                    }],
+                    ..ScopeSpans::default()
+                }],
+                ..ResourceSpans::default()
+            }],
+        }
+    }
+
+    fn headers(content_type: &str, encoding: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        if let Some(encoding) = encoding {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+        }
+        headers
+    }
+
+    // The request `receive_http` decoded, and the response it gave.
+    fn receive(
+        receiver: &Receiver,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> (Option<ExportTraceServiceRequest>, Response) {
+        let mut received = None;
+        let response = receive_http(receiver, headers, body, |request| received = Some(request));
+        (received, response)
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn content_type(response: &Response) -> &str {
+        response.headers()[CONTENT_TYPE].to_str().unwrap()
+    }
+
+    #[test]
+    fn decodes_protobuf_and_answers_in_protobuf() {
+        let body = request().encode_to_vec();
+        let headers = headers("application/x-protobuf", None);
+        let (received, response) = receive(&receiver(1024), &headers, &body);
+        assert_eq!(received, Some(request()));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(content_type(&response), "application/x-protobuf");
+    }
+
+    #[test]
+    fn decodes_json_and_answers_in_json() {
+        // OTLP/JSON spells ids in hex, not base64.
+        let body = r#"{"resourceSpans":[{"scopeSpans":[{"spans":[{
+            "traceId":"01010101010101010101010101010101",
+            "spanId":"0202020202020202",
+            "name":"handler"}]}]}]}"#;
+        let headers = headers("application/json; charset=utf-8", None);
+        let (received, response) = receive(&receiver(1024), &headers, body.as_bytes());
+        assert_eq!(received, Some(request()));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(content_type(&response), "application/json");
+    }
+
+    #[test]
+    fn rejects_other_content_types_and_undecodable_bodies() {
+        let body = request().encode_to_vec();
+        let (received, response) = receive(&receiver(1024), &headers("text/plain", None), &body);
+        assert!(received.is_none());
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let headers = headers("application/json", None);
+        let (received, response) = receive(&receiver(1024), &headers, &body);
+        assert!(received.is_none());
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn decompresses_gzip_and_zstd_bodies() {
+        let body = request().encode_to_vec();
+        let gzipped = gzip(&body);
+        let headers = headers("application/x-protobuf", Some("gzip"));
+        assert_eq!(
+            receive(&receiver(1024), &headers, &gzipped).0,
+            Some(request())
+        );
+
+        let zstd = zstd::encode_all(body.as_slice(), 0).unwrap();
+        let headers = self::headers("application/x-protobuf", Some("zstd"));
+        assert_eq!(receive(&receiver(1024), &headers, &zstd).0, Some(request()));
+    }
+
+    #[test]
+    fn limits_the_decompressed_size() {
+        // A few kilobytes that inflate to a megabyte.
+        let bomb = gzip(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+        let error = decompress(Some("gzip"), &bomb, 64 * 1024).unwrap_err();
+        assert_eq!(error, "the request is larger than 65536 bytes");
+        let zstd_bomb = zstd::encode_all(vec![0; 1024 * 1024].as_slice(), 0).unwrap();
+        assert!(decompress(Some("zstd"), &zstd_bomb, 64 * 1024).is_err());
+
+        let headers = headers("application/x-protobuf", Some("gzip"));
+        let (received, response) = receive(&receiver(64 * 1024), &headers, &bomb);
+        assert!(received.is_none());
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Exactly the limit is still accepted.
+        let body = gzip(&[7; 100]);
+        assert_eq!(decompress(Some("gzip"), &body, 100).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn passes_identity_bodies_through_and_rejects_unknown_encodings() {
+        assert!(matches!(
+            decompress(Some("identity"), b"body", 1),
+            Ok(Cow::Borrowed(b"body"))
+        ));
+        assert!(matches!(decompress(None, b"body", 1), Ok(Cow::Borrowed(_))));
+        assert_eq!(
+            decompress(Some("br"), b"body", 1024).unwrap_err(),
+            "unsupported content encoding \"br\""
+        );
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Collected(Arc<Mutex<Vec<LogRecord>>>);
+
+    #[async_trait]
+    impl LogExporter for Collected {
+        async fn export(&mut self, batch: LogBatch<'_>) -> LogResult<()> {
+            let mut records = self.0.lock().unwrap();
+            records.extend(batch.iter().map(|(record, _)| record.clone()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_log_records_below_the_minimum_level() {
+        let collected = Collected::default();
+        let processor = Shared::new(BatchLogProcessor::builder(collected.clone(), Tokio).build());
+        let pipelines = Pipelines(vec![Pipeline::logs("test", processor.clone())]);
+        let tracer = opentelemetry::global::tracer("test");
+        let tracker = Arc::new(Mutex::new(InvocationTracker::new(tracer)));
+        let receiver = Receiver::new(ReceiverSettings::default(), pipelines, tracker)
+            .with_min_level(Some(LogLevel::Warn));
+
+        let record = |severity: Severity| ProtoRecord {
+            severity_number: severity as i32,
+            ..ProtoRecord::default()
+        };
+        receiver.receive_logs(ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                scope_logs: vec![ScopeLogs {
+                    log_records: vec![
+                        record(Severity::Debug),
+                        record(Severity::Info4),
+                        record(Severity::Warn),
+                        record(Severity::Fatal),
+                        ProtoRecord::default(),
+                    ],
+                    ..ScopeLogs::default()
+                }],
+                ..ResourceLogs::default()
+            }],
+        });
+        tokio::task::spawn_blocking(move || processor.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let severities: Vec<_> = collected
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.severity_number)
+            .collect();
+        assert_eq!(
+            severities,
+            [Some(Severity::Warn), Some(Severity::Fatal), None]
+        );
+    }
+}
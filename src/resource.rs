@@ -0,0 +1,197 @@
+use crate::semconv;
+use async_trait::async_trait;
+use opentelemetry::logs::LogResult;
+use opentelemetry::{Array, KeyValue, StringValue, Value};
+use opentelemetry_sdk::export::logs::{LogBatch, LogExporter};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::resource::ResourceDetector;
+use opentelemetry_sdk::Resource;
+use std::collections::BTreeMap;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, info};
+
+const DETECTOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The service name the function's SDK reported, for when none is configured.
+static FUNCTION_SERVICE_NAME: OnceLock<String> = OnceLock::new();
+
+/// Detects `cloud.*`, `faas.*` and `aws.log.*` resource attributes from the
+/// variables Lambda sets in every execution environment.
+#[derive(Debug, Default)]
+pub struct LambdaResourceDetector;
+
+impl ResourceDetector for LambdaResourceDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        let mut attributes = vec![
+            KeyValue::new(semconv::CLOUD_PROVIDER, "aws"),
+            KeyValue::new(semconv::CLOUD_PLATFORM, "aws_lambda"),
+        ];
+        let vars = [
+            (semconv::CLOUD_REGION, "AWS_REGION"),
+            (semconv::FAAS_NAME, "AWS_LAMBDA_FUNCTION_NAME"),
+            (semconv::FAAS_VERSION, "AWS_LAMBDA_FUNCTION_VERSION"),
+            (semconv::FAAS_INSTANCE, "AWS_LAMBDA_LOG_STREAM_NAME"),
+            (semconv::AWS_LAMBDA_EXECUTION_ENV, "AWS_EXECUTION_ENV"),
+        ];
+        for (key, var) in vars {
+            if let Some(value) = non_empty_var(var) {
+                attributes.push(KeyValue::new(key, value));
+            }
+        }
+
+        if let Some(memory_mb) =
+            non_empty_var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE").and_then(|value| value.parse().ok())
+        {
+            attributes.push(KeyValue::new(
+                semconv::FAAS_MAX_MEMORY,
+                semconv::mb_to_bytes(memory_mb),
+            ));
+        }
+        if let Some(group) = non_empty_var("AWS_LAMBDA_LOG_GROUP_NAME") {
+            attributes.push(KeyValue::new(
+                semconv::AWS_LOG_GROUP_NAMES,
+                string_array(group),
+            ));
+        }
+        if let Some(stream) = non_empty_var("AWS_LAMBDA_LOG_STREAM_NAME") {
+            attributes.push(KeyValue::new(
+                semconv::AWS_LOG_STREAM_NAMES,
+                string_array(stream),
+            ));
+        }
+
+        Resource::new(attributes)
+    }
+}
+
+/// Builds the resource from what Lambda exposes, overlaid by the configured
+/// attributes (including `OTEL_RESOURCE_ATTRIBUTES`) and the service name.
+pub fn detect(service_name: &str, attributes: &BTreeMap<String, String>) -> Resource {
+    let configured = attributes
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()));
+    let resource =
+        Resource::from_detectors(DETECTOR_TIMEOUT, vec![Box::new(LambdaResourceDetector)])
+            .merge(&Resource::new(configured))
+            .merge(&Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name.to_string(),
+            )]));
+    debug!("Detected resource: {:?}", resource);
+    resource
+}
+
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn string_array(value: String) -> Value {
+    Value::Array(Array::String(vec![StringValue::from(value)]))
+}
+
+/// Takes `name`, reported by the function's SDK, as the service name of
+/// everything exported from now on, unless it is the SDK's `unknown_service`
+/// default. Only the first name is taken.
+pub fn adopt_service_name(name: &str) {
+    if name.is_empty() || name.starts_with("unknown_service") {
+        return;
+    }
+    if FUNCTION_SERVICE_NAME.set(name.to_string()).is_ok() {
+        info!("Using the function's service name {:?}", name);
+    }
+}
+
+/// `resource` with the function's service name, once one has been adopted.
+pub fn with_function_service_name(resource: &Resource) -> Option<Resource> {
+    let name = FUNCTION_SERVICE_NAME.get()?;
+    Some(resource.merge(&Resource::new([KeyValue::new(
+        "service.name",
+        name.clone(),
+    )])))
+}
+
+/// Gives `inner` the function's service name as soon as it is adopted.
+#[derive(Debug)]
+pub struct AdoptingSpanExporter<E> {
+    inner: E,
+    resource: Option<Resource>,
+    adopted: bool,
+}
+
+impl<E> AdoptingSpanExporter<E> {
+    pub fn new(inner: E) -> Self {
+        AdoptingSpanExporter {
+            inner,
+            resource: None,
+            adopted: false,
+        }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for AdoptingSpanExporter<E> {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        if !self.adopted {
+            if let Some(resource) = self.resource.as_ref().and_then(with_function_service_name) {
+                self.inner.set_resource(&resource);
+                self.adopted = true;
+            }
+        }
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = Some(resource.clone());
+        self.inner.set_resource(resource)
+    }
+}
+
+/// Gives `inner` the function's service name as soon as it is adopted.
+#[derive(Debug)]
+pub struct AdoptingLogExporter<E> {
+    inner: E,
+    resource: Option<Resource>,
+    adopted: bool,
+}
+
+impl<E> AdoptingLogExporter<E> {
+    pub fn new(inner: E) -> Self {
+        AdoptingLogExporter {
+            inner,
+            resource: None,
+            adopted: false,
+        }
+    }
+}
+
+#[async_trait]
+impl<E: LogExporter> LogExporter for AdoptingLogExporter<E> {
+    async fn export(&mut self, batch: LogBatch<'_>) -> LogResult<()> {
+        if !self.adopted {
+            if let Some(resource) = self.resource.as_ref().and_then(with_function_service_name) {
+                self.inner.set_resource(&resource);
+                self.adopted = true;
+            }
+        }
+        self.inner.export(batch).await
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = Some(resource.clone());
+        self.inner.set_resource(resource)
+    }
+}
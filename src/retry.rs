@@ -0,0 +1,895 @@
+//! Retries for exports that failed in a way the collector may recover from,
+//! with exponential backoff and jitter, honouring `Retry-After`. Retries
+//! stop short of the flush or shutdown deadline, so they never keep the
+//! invocation running longer than it would have; batches that could not be
+//! sent by then wait in a bounded in-memory queue for the next export.
+
+use crate::exporter::StatusError;
+use crate::spill;
+use async_trait::async_trait;
+use http::StatusCode;
+use opentelemetry::logs::{AnyValue, LogError, LogResult};
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::trace::TraceError;
+use opentelemetry::InstrumentationLibrary;
+use opentelemetry_sdk::export::logs::{LogBatch, LogExporter};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::logs::LogRecord;
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, TemporalitySelector};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind};
+use opentelemetry_sdk::Resource;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+// Kept back from the deadline: no attempt is started or left running past
+// it, so what follows the export still fits.
+const ATTEMPT_RESERVE: Duration = Duration::from_millis(100);
+
+static DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Limits retries to `deadline`, or lifts the limit with `None`. Set while
+/// a flush or shutdown is waiting on the exporters.
+pub fn set_deadline(deadline: Option<Instant>) {
+    *DEADLINE.lock().unwrap() = deadline;
+}
+
+/// The deadline set by [`set_deadline`], if any.
+pub fn deadline() -> Option<Instant> {
+    *DEADLINE.lock().unwrap()
+}
+
+/// How failed exports are retried.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySettings {
+    pub enabled: bool,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// How long one export keeps retrying when no deadline is near.
+    pub max_elapsed_ms: u64,
+    /// Approximate memory kept for spans and logs that could not be sent
+    /// before the deadline. The oldest are dropped first.
+    pub queue_max_bytes: usize,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            enabled: true,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 5_000,
+            max_elapsed_ms: 10_000,
+            queue_max_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl RetrySettings {
+    /// Problems with these settings, found at `path` in the configuration.
+    pub fn validate(&self, path: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.initial_backoff_ms == 0 {
+            problems.push(format!(
+                "{}.initial_backoff_ms must be greater than 0",
+                path
+            ));
+        }
+        if self.max_backoff_ms < self.initial_backoff_ms {
+            problems.push(format!(
+                "{}.max_backoff_ms must be at least initial_backoff_ms",
+                path
+            ));
+        }
+        problems
+    }
+}
+
+/// Why an export was given up on.
+enum GaveUp<E> {
+    /// The error is not worth retrying.
+    Permanent(E),
+    /// Another attempt would not fit before the deadline.
+    OutOfTime(E),
+}
+
+/// Runs `attempt` until it succeeds, fails permanently or runs out of time.
+/// An attempt still running at the deadline is abandoned and counts as run
+/// out of time, or as failed for good when retries are off.
+async fn with_retries<E, F, Fut>(settings: RetrySettings, mut attempt: F) -> Result<(), GaveUp<E>>
+where
+    E: Retryable + fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let started = Instant::now();
+    let mut backoff = Duration::from_millis(settings.initial_backoff_ms);
+    loop {
+        let result = match deadline() {
+            Some(deadline) => {
+                let limit = deadline.checked_sub(ATTEMPT_RESERVE).unwrap_or(deadline);
+                match tokio::time::timeout_at(limit.into(), attempt()).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let error = E::timed_out(started.elapsed());
+                        return Err(if settings.enabled {
+                            GaveUp::OutOfTime(error)
+                        } else {
+                            GaveUp::Permanent(error)
+                        });
+                    }
+                }
+            }
+            None => attempt().await,
+        };
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        let retry_after = match error.retry() {
+            Some(retry_after) if settings.enabled => retry_after,
+            _ => return Err(GaveUp::Permanent(error)),
+        };
+        let delay = retry_after.unwrap_or_else(|| jitter(backoff));
+        backoff = (backoff * 2).min(Duration::from_millis(settings.max_backoff_ms));
+
+        let next_attempt = Instant::now() + delay;
+        let limit = match deadline() {
+            Some(deadline) => deadline.checked_sub(ATTEMPT_RESERVE).unwrap_or(deadline),
+            None => started + Duration::from_millis(settings.max_elapsed_ms),
+        };
+        if next_attempt >= limit {
+            return Err(GaveUp::OutOfTime(error));
+        }
+        debug!("Export failed ({}), retrying in {:?}", error, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Somewhere between half and all of the backoff, so destinations recovering
+// from an outage are not hit by every environment at once.
+fn jitter(backoff: Duration) -> Duration {
+    let millis = backoff.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+}
+
+/// An export error that may be worth retrying.
+trait Retryable {
+    /// `Some` with the wait the collector asked for, if any, when another
+    /// attempt may succeed.
+    fn retry(&self) -> Option<Option<Duration>>;
+
+    /// The error for an export abandoned at the deadline after `elapsed`.
+    fn timed_out(elapsed: Duration) -> Self;
+}
+
+impl Retryable for TraceError {
+    fn retry(&self) -> Option<Option<Duration>> {
+        match self {
+            TraceError::ExportFailed(e) => retry(e.as_ref()),
+            TraceError::Other(e) => retry(e.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn timed_out(elapsed: Duration) -> Self {
+        TraceError::ExportTimedOut(elapsed)
+    }
+}
+
+impl Retryable for LogError {
+    fn retry(&self) -> Option<Option<Duration>> {
+        match self {
+            LogError::ExportFailed(e) => retry(e.as_ref()),
+            LogError::Other(e) => retry(e.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn timed_out(elapsed: Duration) -> Self {
+        LogError::ExportTimedOut(elapsed)
+    }
+}
+
+impl Retryable for MetricsError {
+    fn retry(&self) -> Option<Option<Duration>> {
+        match self {
+            MetricsError::ExportErr(e) => retry(e.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn timed_out(elapsed: Duration) -> Self {
+        MetricsError::Other(format!("export timed out after {:?}", elapsed))
+    }
+}
+
+// Connection failures, 429, 502, 503, 504 and gRPC UNAVAILABLE, as the
+// OTLP specification suggests.
+fn retry(error: &(dyn Error + 'static)) -> Option<Option<Duration>> {
+    if let Some(error) = error.downcast_ref::<opentelemetry_otlp::Error>() {
+        return match error {
+            opentelemetry_otlp::Error::Status { code, .. } => {
+                (*code == tonic::Code::Unavailable).then_some(None)
+            }
+            opentelemetry_otlp::Error::Transport(_) => Some(None),
+            opentelemetry_otlp::Error::RequestFailed(e) => retry(e.as_ref()),
+            _ => None,
+        };
+    }
+    if let Some(error) = error.downcast_ref::<StatusError>() {
+        return matches!(
+            error.status,
+            StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        )
+        .then_some(error.retry_after);
+    }
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return (error.is_connect() || error.is_timeout()).then_some(None);
+    }
+    None
+}
+
+/// Batches waiting to be sent again, oldest first.
+#[derive(Debug)]
+struct Queue<T> {
+    batches: VecDeque<(T, usize)>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<T> Queue<T> {
+    fn new(max_bytes: usize) -> Self {
+        Queue {
+            batches: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn take(&mut self) -> VecDeque<(T, usize)> {
+        self.bytes = 0;
+        std::mem::take(&mut self.batches)
+    }
+
+    /// Queues `batches` in front of anything queued since they were taken,
+    /// dropping the oldest batches beyond the memory budget.
+    fn restore(&mut self, mut batches: VecDeque<(T, usize)>) {
+        batches.append(&mut self.batches);
+        self.bytes = batches.iter().map(|(_, size)| size).sum();
+        self.batches = batches;
+        self.evict();
+    }
+
+    /// Queues a batch behind the others, dropping the oldest batches beyond
+    /// the memory budget.
+    fn push(&mut self, batch: T, size: usize) {
+        self.batches.push_back((batch, size));
+        self.bytes += size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes {
+            let Some((_, size)) = self.batches.pop_front() else {
+                break;
+            };
+            self.bytes -= size;
+            dropped += 1;
+        }
+        if dropped > 0 {
+            warn!(
+                "Dropped {} unsent batch(es) to stay within {} bytes",
+                dropped, self.max_bytes
+            );
+        }
+    }
+}
+
+// Roughly what an attribute, event or link holds beyond the struct it sits
+// in: a short key and value.
+const ENTRY_BYTES: usize = 64;
+
+/// A batch whose memory can be estimated cheaply, for the queue's budget.
+trait ApproximateSize {
+    fn approximate_size(&self) -> usize;
+}
+
+impl ApproximateSize for Vec<SpanData> {
+    fn approximate_size(&self) -> usize {
+        self.iter()
+            .map(|span| {
+                let entries = span.attributes.len()
+                    + span.links.links.len()
+                    + span
+                        .events
+                        .events
+                        .iter()
+                        .map(|event| 1 + event.attributes.len())
+                        .sum::<usize>();
+                mem::size_of::<SpanData>() + entries * ENTRY_BYTES
+            })
+            .sum()
+    }
+}
+
+impl ApproximateSize for Vec<(LogRecord, InstrumentationLibrary)> {
+    fn approximate_size(&self) -> usize {
+        self.iter()
+            .map(|(record, _)| {
+                let body = match &record.body {
+                    Some(AnyValue::String(body)) => body.as_str().len(),
+                    Some(_) => ENTRY_BYTES,
+                    None => 0,
+                };
+                mem::size_of::<LogRecord>() + body + record.attributes_iter().count() * ENTRY_BYTES
+            })
+            .sum()
+    }
+}
+
+/// Sends what is queued, in order. Stops at the first batch that runs out
+/// of time and queues it again with everything after it; batches that fail
+/// permanently are dropped and the rest still sent.
+async fn send_queued<T, E, F, Fut>(
+    queue: &Mutex<Queue<T>>,
+    settings: RetrySettings,
+    mut send: F,
+) -> Result<(), GaveUp<E>>
+where
+    E: Retryable + fmt::Display,
+    F: FnMut(&T) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let mut pending = queue.lock().unwrap().take();
+    let mut result = Ok(());
+    while let Some((batch, size)) = pending.pop_front() {
+        match with_retries(settings, || send(&batch)).await {
+            Ok(()) => {}
+            Err(GaveUp::Permanent(e)) => {
+                error!("Dropping a batch the collector will not accept: {}", e);
+                result = Err(GaveUp::Permanent(e));
+            }
+            Err(GaveUp::OutOfTime(e)) => {
+                pending.push_front((batch, size));
+                warn!(
+                    "Export still failing ({}), keeping {} queued batch(es) for the next one",
+                    e,
+                    pending.len()
+                );
+                queue.lock().unwrap().restore(pending);
+                return Err(GaveUp::OutOfTime(e));
+            }
+        }
+    }
+    result
+}
+
+// Queues a batch that could not be sent in time behind the ones already
+// waiting.
+fn keep<T: ApproximateSize>(queue: &Mutex<Queue<T>>, batch: T, error: &impl fmt::Display) {
+    warn!(
+        "Export still failing ({}), keeping the batch for the next one",
+        error
+    );
+    let size = batch.approximate_size();
+    queue.lock().unwrap().push(batch, size);
+}
+
+// What became of the batches still queued at shutdown.
+fn report_spill<E: fmt::Display>(count: usize, spilled: Option<Result<(), E>>) {
+    match spilled {
+        Some(Ok(())) => info!("Spilled {} unsent batch(es) to disk", count),
+        Some(Err(e)) => error!("Failed to spill unsent batches to disk: {}", e),
+        None => warn!(
+            "Cannot spill from this thread, dropping {} unsent batch(es)",
+            count
+        ),
+    }
+}
+
+/// Retries span exports and queues the batches that run out of time.
+#[derive(Debug)]
+pub struct RetryingSpanExporter<E> {
+    inner: Arc<tokio::sync::Mutex<E>>,
+    queue: Arc<Mutex<Queue<Vec<SpanData>>>>,
+    settings: RetrySettings,
+    spill: bool,
+}
+
+impl<E> RetryingSpanExporter<E> {
+    pub fn new(inner: E, settings: RetrySettings) -> Self {
+        RetryingSpanExporter {
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            queue: Arc::new(Mutex::new(Queue::new(settings.queue_max_bytes))),
+            settings,
+            spill: false,
+        }
+    }
+
+    /// Whether queued batches are spilled to disk at shutdown instead of
+    /// dropped. Only for exporters given a spool.
+    pub fn with_spill(mut self, spill: bool) -> Self {
+        self.spill = spill;
+        self
+    }
+}
+
+impl<E: SpanExporter + 'static> SpanExporter for RetryingSpanExporter<E> {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let inner = self.inner.clone();
+        let queue = self.queue.clone();
+        let settings = self.settings;
+        Box::pin(async move {
+            let export = |batch| {
+                let inner = inner.clone();
+                async move {
+                    let export = inner.lock().await.export(batch);
+                    export.await
+                }
+            };
+            if let Err(GaveUp::OutOfTime(e)) =
+                send_queued(&queue, settings, |batch| export(batch.clone())).await
+            {
+                keep(&queue, batch, &e);
+                return Err(e);
+            }
+
+            // The exporter takes the batch, so a copy is only kept while a
+            // retry or the queue may still need it.
+            let mut batch = Some(batch);
+            let result = with_retries(settings, || {
+                let attempt = if settings.enabled {
+                    batch.clone()
+                } else {
+                    batch.take()
+                };
+                export(attempt.unwrap_or_default())
+            })
+            .await;
+            match result {
+                Ok(()) => Ok(()),
+                Err(GaveUp::Permanent(e)) => Err(e),
+                Err(GaveUp::OutOfTime(e)) => {
+                    keep(&queue, batch.unwrap_or_default(), &e);
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    fn shutdown(&mut self) {
+        if let Ok(mut inner) = self.inner.try_lock() {
+            let batches = self.queue.lock().unwrap().take();
+            if self.spill && !batches.is_empty() {
+                let count = batches.len();
+                let spilled = spill::capture(async {
+                    for (batch, _) in batches {
+                        inner.export(batch).await?;
+                    }
+                    Ok::<_, TraceError>(())
+                });
+                report_spill(count, spilled);
+            }
+            inner.shutdown();
+        }
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        if let Ok(mut inner) = self.inner.try_lock() {
+            inner.set_resource(resource);
+        }
+    }
+}
+
+/// Retries log exports and queues the batches that run out of time.
+#[derive(Debug)]
+pub struct RetryingLogExporter<E> {
+    inner: tokio::sync::Mutex<E>,
+    queue: Mutex<Queue<Vec<(LogRecord, InstrumentationLibrary)>>>,
+    settings: RetrySettings,
+    spill: bool,
+}
+
+impl<E> RetryingLogExporter<E> {
+    pub fn new(inner: E, settings: RetrySettings) -> Self {
+        RetryingLogExporter {
+            inner: tokio::sync::Mutex::new(inner),
+            queue: Mutex::new(Queue::new(settings.queue_max_bytes)),
+            settings,
+            spill: false,
+        }
+    }
+
+    /// Whether queued batches are spilled to disk at shutdown instead of
+    /// dropped. Only for exporters given a spool.
+    pub fn with_spill(mut self, spill: bool) -> Self {
+        self.spill = spill;
+        self
+    }
+}
+
+#[async_trait]
+impl<E: LogExporter> LogExporter for RetryingLogExporter<E> {
+    async fn export(&mut self, batch: LogBatch<'_>) -> LogResult<()> {
+        let inner = &self.inner;
+        let queued = send_queued(&self.queue, self.settings, |batch| {
+            let batch = batch.clone();
+            async move {
+                let records: Vec<_> = batch.iter().map(|(r, l)| (r, l)).collect();
+                inner.lock().await.export(LogBatch::new(&records)).await
+            }
+        })
+        .await;
+        if let Err(GaveUp::OutOfTime(e)) = queued {
+            keep(&self.queue, owned(&batch), &e);
+            return Err(e);
+        }
+
+        let records: Vec<_> = batch.iter().collect();
+        let records = &records;
+        let result = with_retries(self.settings, move || async move {
+            inner.lock().await.export(LogBatch::new(records)).await
+        })
+        .await;
+        match result {
+            Ok(()) => Ok(()),
+            Err(GaveUp::Permanent(e)) => Err(e),
+            Err(GaveUp::OutOfTime(e)) => {
+                keep(&self.queue, owned(&batch), &e);
+                Err(e)
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        let inner = self.inner.get_mut();
+        let batches = self.queue.get_mut().unwrap().take();
+        if self.spill && !batches.is_empty() {
+            let count = batches.len();
+            let spilled = spill::capture(async {
+                for (batch, _) in &batches {
+                    let records: Vec<_> = batch.iter().map(|(r, l)| (r, l)).collect();
+                    inner.export(LogBatch::new(&records)).await?;
+                }
+                Ok::<_, LogError>(())
+            });
+            report_spill(count, spilled);
+        }
+        inner.shutdown();
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.get_mut().set_resource(resource);
+    }
+}
+
+// The SDK lends the records, so a batch is only copied once it has to wait
+// for a later export.
+fn owned(batch: &LogBatch<'_>) -> Vec<(LogRecord, InstrumentationLibrary)> {
+    batch
+        .iter()
+        .map(|(record, library)| (record.clone(), library.clone()))
+        .collect()
+}
+
+/// Retries metric exports. Nothing is queued: with cumulative temporality
+/// the next export carries everything a lost one would have.
+#[derive(Debug)]
+pub struct RetryingMetricsExporter<E> {
+    inner: E,
+    settings: RetrySettings,
+}
+
+impl<E> RetryingMetricsExporter<E> {
+    pub fn new(inner: E, settings: RetrySettings) -> Self {
+        RetryingMetricsExporter { inner, settings }
+    }
+}
+
+impl<E: TemporalitySelector> TemporalitySelector for RetryingMetricsExporter<E> {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.inner.temporality(kind)
+    }
+}
+
+impl<E: AggregationSelector> AggregationSelector for RetryingMetricsExporter<E> {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.inner.aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl<E: PushMetricsExporter> PushMetricsExporter for RetryingMetricsExporter<E> {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        let metrics = &tokio::sync::Mutex::new(metrics);
+        let inner = &self.inner;
+        let result = with_retries(self.settings, move || async move {
+            inner.export(*metrics.lock().await).await
+        })
+        .await;
+        match result {
+            Ok(()) => Ok(()),
+            Err(GaveUp::Permanent(e) | GaveUp::OutOfTime(e)) => Err(e),
+        }
+    }
+
+    async fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        self.inner.force_flush().await
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, SpanKind, Status};
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+
+    // The deadline is process wide, so tests that set it take turns.
+    static DEADLINE_SET: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    fn settings() -> RetrySettings {
+        RetrySettings {
+            initial_backoff_ms: 10,
+            max_backoff_ms: 40,
+            max_elapsed_ms: 1_000,
+            ..RetrySettings::default()
+        }
+    }
+
+    fn status(status: StatusCode) -> TraceError {
+        TraceError::Other(Box::new(StatusError {
+            status,
+            retry_after: None,
+        }))
+    }
+
+    fn span(name: &'static str) -> SpanData {
+        SpanData {
+            span_context: SpanContext::empty_context(),
+            parent_span_id: opentelemetry::trace::SpanId::INVALID,
+            span_kind: SpanKind::Internal,
+            name: Cow::Borrowed(name),
+            start_time: SystemTime::UNIX_EPOCH,
+            end_time: SystemTime::UNIX_EPOCH,
+            attributes: Vec::new(),
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status: Status::Unset,
+            instrumentation_lib: InstrumentationLibrary::default(),
+        }
+    }
+
+    #[test]
+    fn retries_unavailable_collectors() {
+        for code in [
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ] {
+            assert_eq!(status(code).retry(), Some(None), "{}", code);
+        }
+        let error = StatusError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::from_secs(3)),
+        };
+        assert_eq!(retry(&error), Some(Some(Duration::from_secs(3))));
+
+        let unavailable = opentelemetry_otlp::Error::Status {
+            code: tonic::Code::Unavailable,
+            message: String::new(),
+        };
+        assert_eq!(retry(&unavailable), Some(None));
+        let transport = opentelemetry_otlp::Error::RequestFailed(Box::new(StatusError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: None,
+        }));
+        assert_eq!(retry(&transport), Some(None));
+    }
+
+    #[test]
+    fn does_not_retry_rejected_exports() {
+        for code in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            assert_eq!(status(code).retry(), None, "{}", code);
+        }
+        let invalid = opentelemetry_otlp::Error::Status {
+            code: tonic::Code::InvalidArgument,
+            message: String::new(),
+        };
+        assert_eq!(retry(&invalid), None);
+        assert_eq!(TraceError::from("unknown").retry(), None);
+        assert_eq!(TraceError::timed_out(Duration::from_secs(1)).retry(), None);
+    }
+
+    #[test]
+    fn queue_drops_the_oldest_batches_beyond_its_budget() {
+        let mut queue = Queue::new(100);
+        queue.push("a", 40);
+        queue.push("b", 40);
+        queue.push("c", 40);
+        assert_eq!(queue.bytes, 80);
+        let batches: Vec<_> = queue.take().into_iter().collect();
+        assert_eq!(batches, [("b", 40), ("c", 40)]);
+        assert_eq!(queue.bytes, 0);
+
+        queue.restore(VecDeque::from([("oldest", 40), ("older", 40)]));
+        queue.push("new", 40);
+        let batches: Vec<_> = queue.take().into_iter().map(|(batch, _)| batch).collect();
+        assert_eq!(batches, ["older", "new"]);
+
+        queue.push("huge", 500);
+        assert!(queue.take().is_empty());
+    }
+
+    #[test]
+    fn restored_batches_go_before_those_queued_meanwhile() {
+        let mut queue = Queue::new(1_000);
+        queue.push("a", 1);
+        queue.push("b", 1);
+        let taken = queue.take();
+        queue.push("c", 1);
+        queue.restore(taken);
+        let batches: Vec<_> = queue.take().into_iter().map(|(batch, _)| batch).collect();
+        assert_eq!(batches, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn estimates_span_batches_from_their_entries() {
+        let mut busy = span("busy");
+        busy.attributes = vec![opentelemetry::KeyValue::new("a", 1); 3];
+        let plain = vec![span("plain")].approximate_size();
+        assert_eq!(plain, mem::size_of::<SpanData>());
+        assert_eq!(vec![busy].approximate_size(), plain + 3 * ENTRY_BYTES);
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_export_succeeds() {
+        let _guard = DEADLINE_SET.lock().await;
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(settings(), || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(status(StatusCode::SERVICE_UNAVAILABLE)),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_permanent_errors_and_when_disabled() {
+        let _guard = DEADLINE_SET.lock().await;
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(settings(), || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(status(StatusCode::BAD_REQUEST))
+        })
+        .await;
+        assert!(matches!(result, Err(GaveUp::Permanent(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let disabled = RetrySettings {
+            enabled: false,
+            ..settings()
+        };
+        let result = with_retries(disabled, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(status(StatusCode::SERVICE_UNAVAILABLE))
+        })
+        .await;
+        assert!(matches!(result, Err(GaveUp::Permanent(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_before_the_deadline() {
+        let _guard = DEADLINE_SET.lock().await;
+        let deadline = Instant::now() + Duration::from_millis(300);
+        set_deadline(Some(deadline));
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(settings(), || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(status(StatusCode::SERVICE_UNAVAILABLE))
+        })
+        .await;
+        set_deadline(None);
+        assert!(matches!(result, Err(GaveUp::OutOfTime(_))));
+        assert!(attempts.load(Ordering::SeqCst) > 1);
+        assert!(Instant::now() < deadline);
+    }
+
+    #[tokio::test]
+    async fn abandons_an_attempt_still_running_at_the_deadline() {
+        let _guard = DEADLINE_SET.lock().await;
+        let deadline = Instant::now() + Duration::from_millis(300);
+        set_deadline(Some(deadline));
+        let result = with_retries(settings(), || async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok::<_, TraceError>(())
+        })
+        .await;
+        set_deadline(None);
+        assert!(matches!(
+            result,
+            Err(GaveUp::OutOfTime(TraceError::ExportTimedOut(_)))
+        ));
+        assert!(Instant::now() < deadline);
+    }
+
+    // Fails while `failing` is set, recording the names of the spans it was
+    // given otherwise.
+    #[derive(Debug, Default)]
+    struct Collector {
+        failing: Arc<Mutex<bool>>,
+        exported: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl SpanExporter for Collector {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            let result = if *self.failing.lock().unwrap() {
+                Err(status(StatusCode::SERVICE_UNAVAILABLE))
+            } else {
+                let names = batch.iter().map(|span| span.name.to_string()).collect();
+                self.exported.lock().unwrap().push(names);
+                Ok(())
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn queues_batches_that_run_out_of_time_and_sends_them_first() {
+        let _guard = DEADLINE_SET.lock().await;
+        let collector = Collector::default();
+        let failing = collector.failing.clone();
+        let exported = collector.exported.clone();
+        let mut exporter = RetryingSpanExporter::new(collector, settings());
+
+        *failing.lock().unwrap() = true;
+        set_deadline(Some(Instant::now() + Duration::from_millis(200)));
+        let result = exporter.export(vec![span("first")]).await;
+        set_deadline(None);
+        assert!(result.is_err());
+        assert!(exported.lock().unwrap().is_empty());
+
+        *failing.lock().unwrap() = false;
+        exporter.export(vec![span("second")]).await.unwrap();
+        assert_eq!(*exported.lock().unwrap(), [vec!["first"], vec!["second"]]);
+    }
+}
@@ -0,0 +1,86 @@
+use opentelemetry_sdk::trace::Sampler;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Trace samplers, named as in `OTEL_TRACES_SAMPLER`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum SamplerKind {
+    #[serde(rename = "always_on")]
+    AlwaysOn,
+    #[serde(rename = "always_off")]
+    AlwaysOff,
+    #[serde(rename = "traceidratio")]
+    TraceIdRatio,
+    #[default]
+    #[serde(rename = "parentbased_always_on")]
+    ParentBasedAlwaysOn,
+    #[serde(rename = "parentbased_always_off")]
+    ParentBasedAlwaysOff,
+    #[serde(rename = "parentbased_traceidratio")]
+    ParentBasedTraceIdRatio,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "always_on" => Ok(SamplerKind::AlwaysOn),
+            "always_off" => Ok(SamplerKind::AlwaysOff),
+            "traceidratio" => Ok(SamplerKind::TraceIdRatio),
+            "parentbased_always_on" => Ok(SamplerKind::ParentBasedAlwaysOn),
+            "parentbased_always_off" => Ok(SamplerKind::ParentBasedAlwaysOff),
+            "parentbased_traceidratio" => Ok(SamplerKind::ParentBasedTraceIdRatio),
+            other => Err(format!(
+                "unsupported sampler {:?}, expected always_on, always_off, traceidratio, \
+                 parentbased_always_on, parentbased_always_off or parentbased_traceidratio",
+                other
+            )),
+        }
+    }
+}
+
+/// Which invocation traces are recorded. The parent-based samplers follow
+/// the X-Ray sampling decision whenever the invocation carries one.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sampling {
+    pub sampler: SamplerKind,
+    /// Fraction of traces kept by the ratio samplers.
+    pub ratio: f64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            sampler: SamplerKind::default(),
+            ratio: 1.0,
+        }
+    }
+}
+
+impl Sampling {
+    pub fn sampler(&self) -> Sampler {
+        match self.sampler {
+            SamplerKind::AlwaysOn => Sampler::AlwaysOn,
+            SamplerKind::AlwaysOff => Sampler::AlwaysOff,
+            SamplerKind::TraceIdRatio => Sampler::TraceIdRatioBased(self.ratio),
+            SamplerKind::ParentBasedAlwaysOn => Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+            SamplerKind::ParentBasedAlwaysOff => Sampler::ParentBased(Box::new(Sampler::AlwaysOff)),
+            SamplerKind::ParentBasedTraceIdRatio => {
+                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.ratio)))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        if (0.0..=1.0).contains(&self.ratio) {
+            Vec::new()
+        } else {
+            vec![format!(
+                "sampling.ratio is {}, expected 0.0 to 1.0",
+                self.ratio
+            )]
+        }
+    }
+}
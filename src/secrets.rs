@@ -0,0 +1,511 @@
+//! `secretsmanager:` and `ssm:` references in configuration values, fetched
+//! once during INIT with the sandbox credentials and cached for the lifetime
+//! of the execution environment. When the collector rejects the credentials
+//! the cached values are fetched again, in case the secret was rotated.
+//!
+//! The AWS SDK's standard settings apply, so `AWS_ENDPOINT_URL` (or
+//! `AWS_ENDPOINT_URL_SECRETS_MANAGER` and `AWS_ENDPOINT_URL_SSM`) points the
+//! lookups at a local mock.
+
+use aws_config::timeout::TimeoutConfig;
+use aws_config::BehaviorVersion;
+use opentelemetry::global;
+use opentelemetry::logs::LogError;
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::trace::TraceError;
+use opentelemetry::ExportError;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
+
+const SECRETS_MANAGER_PREFIX: &str = "secretsmanager:";
+const SSM_PREFIX: &str = "ssm:";
+// Lookups run during INIT, which Lambda limits to ten seconds.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+// A collector that keeps rejecting the credentials should not turn every
+// export into a round of AWS calls.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A value held in Secrets Manager or Parameter Store. Written as
+/// `secretsmanager:<secret id or ARN>[#<JSON key>]` or `ssm:<parameter name>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SecretRef {
+    SecretsManager { id: String, key: Option<String> },
+    Parameter { name: String },
+}
+
+impl SecretRef {
+    /// `None` when `value` is not a reference and should be used as it is.
+    pub fn parse(value: &str) -> Option<Result<SecretRef, String>> {
+        let value = value.trim();
+        if let Some(rest) = value.strip_prefix(SECRETS_MANAGER_PREFIX) {
+            let (id, key) = match rest.split_once('#') {
+                Some((id, key)) => (id, Some(key.to_string())),
+                None => (rest, None),
+            };
+            if id.is_empty() || key.as_deref() == Some("") {
+                return Some(Err(format!(
+                    "{:?} is not a valid reference, expected secretsmanager:<secret id>[#<key>]",
+                    value
+                )));
+            }
+            Some(Ok(SecretRef::SecretsManager {
+                id: id.to_string(),
+                key,
+            }))
+        } else if let Some(name) = value.strip_prefix(SSM_PREFIX) {
+            if name.is_empty() {
+                return Some(Err(format!(
+                    "{:?} is not a valid reference, expected ssm:<parameter name>",
+                    value
+                )));
+            }
+            Some(Ok(SecretRef::Parameter {
+                name: name.to_string(),
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretRef::SecretsManager { id, key: None } => {
+                write!(f, "{}{}", SECRETS_MANAGER_PREFIX, id)
+            }
+            SecretRef::SecretsManager { id, key: Some(key) } => {
+                write!(f, "{}{}#{}", SECRETS_MANAGER_PREFIX, id, key)
+            }
+            SecretRef::Parameter { name } => write!(f, "{}{}", SSM_PREFIX, name),
+        }
+    }
+}
+
+struct Clients {
+    secrets_manager: aws_sdk_secretsmanager::Client,
+    ssm: aws_sdk_ssm::Client,
+}
+
+/// Fetched secret values, shared by the configuration and the exporters.
+#[derive(Default)]
+pub struct Secrets {
+    clients: OnceCell<Clients>,
+    cache: RwLock<HashMap<SecretRef, String>>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+/// The store for this execution environment.
+pub fn global() -> &'static Secrets {
+    static SECRETS: OnceLock<Secrets> = OnceLock::new();
+    SECRETS.get_or_init(Secrets::default)
+}
+
+impl Secrets {
+    /// The value `reference` points to, fetched on first use.
+    pub async fn resolve(&self, reference: &SecretRef) -> Result<String, String> {
+        if let Some(value) = self.cached(reference) {
+            return Ok(value);
+        }
+        let value = self.fetch(reference).await?;
+        self.cache
+            .write()
+            .unwrap()
+            .insert(reference.clone(), value.clone());
+        Ok(value)
+    }
+
+    /// The latest value fetched for `reference`, if any.
+    pub fn cached(&self, reference: &SecretRef) -> Option<String> {
+        self.cache.read().unwrap().get(reference).cloned()
+    }
+
+    /// Fetches every cached value again in the background, unless that was
+    /// already done within the last [`MIN_REFRESH_INTERVAL`].
+    pub fn refresh(&'static self) {
+        if self.cache.read().unwrap().is_empty() {
+            return;
+        }
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if last_refresh.is_some_and(|last| last.elapsed() < MIN_REFRESH_INTERVAL) {
+                debug!("Secrets were refreshed recently, not fetching them again");
+                return;
+            }
+            *last_refresh = Some(Instant::now());
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("No runtime to refresh secrets on");
+            return;
+        };
+        runtime.spawn(async move {
+            let references: Vec<SecretRef> = self.cache.read().unwrap().keys().cloned().collect();
+            let mut refreshed = 0;
+            for reference in references {
+                match self.fetch(&reference).await {
+                    Ok(value) => {
+                        self.cache.write().unwrap().insert(reference, value);
+                        refreshed += 1;
+                    }
+                    Err(e) => warn!("Failed to refresh {}: {}", reference, e),
+                }
+            }
+            info!(
+                "Refreshed {} secret(s) after the collector rejected the credentials",
+                refreshed
+            );
+        });
+    }
+
+    async fn fetch(&self, reference: &SecretRef) -> Result<String, String> {
+        let clients = self.clients.get_or_init(connect).await;
+        match reference {
+            SecretRef::SecretsManager { id, key } => {
+                let output = clients
+                    .secrets_manager
+                    .get_secret_value()
+                    .secret_id(id)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        format!(
+                            "cannot read {}: {}",
+                            reference,
+                            aws_sdk_secretsmanager::error::DisplayErrorContext(e)
+                        )
+                    })?;
+                let secret = output
+                    .secret_string()
+                    .ok_or_else(|| format!("{} is not a string secret", reference))?;
+                match key {
+                    None => Ok(secret.to_string()),
+                    Some(key) => json_field(secret, key)
+                        .ok_or_else(|| format!("{} has no {:?} key", reference, key)),
+                }
+            }
+            SecretRef::Parameter { name } => clients
+                .ssm
+                .get_parameter()
+                .name(name)
+                .with_decryption(true)
+                .send()
+                .await
+                .map_err(|e| {
+                    format!(
+                        "cannot read {}: {}",
+                        reference,
+                        aws_sdk_ssm::error::DisplayErrorContext(e)
+                    )
+                })?
+                .parameter()
+                .and_then(|parameter| parameter.value())
+                .map(str::to_string)
+                .ok_or_else(|| format!("{} has no value", reference)),
+        }
+    }
+}
+
+async fn connect() -> Clients {
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .timeout_config(
+            TimeoutConfig::builder()
+                .operation_timeout(FETCH_TIMEOUT)
+                .build(),
+        )
+        .load()
+        .await;
+    Clients {
+        secrets_manager: aws_sdk_secretsmanager::Client::new(&config),
+        ssm: aws_sdk_ssm::Client::new(&config),
+    }
+}
+
+// Secrets Manager keeps key/value secrets as a JSON object.
+fn json_field(secret: &str, key: &str) -> Option<String> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(secret).ok()?;
+    match object.get(key)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Refreshes the secrets if an export failed because the collector rejected
+/// the credentials. HTTP responses are checked by the exporter's client, so
+/// this only needs to recognise gRPC statuses.
+pub fn on_export_error(error: &global::Error) {
+    if is_auth_failure(error) {
+        global().refresh();
+    }
+}
+
+fn is_auth_failure(error: &global::Error) -> bool {
+    let error: &dyn ExportError = match error {
+        global::Error::Trace(TraceError::ExportFailed(e)) => e.as_ref(),
+        global::Error::Metric(MetricsError::ExportErr(e)) => e.as_ref(),
+        global::Error::Log(LogError::ExportFailed(e)) => e.as_ref(),
+        _ => return false,
+    };
+    let error: &(dyn std::error::Error + 'static) = error;
+    matches!(
+        error.downcast_ref::<opentelemetry_otlp::Error>(),
+        Some(opentelemetry_otlp::Error::Status {
+            code: tonic::Code::Unauthenticated | tonic::Code::PermissionDenied,
+            ..
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_secretsmanager::config::{Credentials, Region};
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    /// A stand-in for the Secrets Manager and Parameter Store APIs, which
+    /// both take a JSON body naming the operation in `X-Amz-Target`.
+    #[derive(Clone, Default)]
+    struct Stub {
+        values: Arc<Mutex<HashMap<String, String>>>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Stub {
+        fn set(&self, name: &str, value: &str) {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), value.to_string());
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        async fn serve(&self) -> String {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let app = Router::new()
+                .route("/", post(Stub::handle))
+                .with_state(self.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            format!("http://{}", address)
+        }
+
+        async fn handle(State(stub): State<Stub>, headers: HeaderMap, body: String) -> Response {
+            let target = headers["x-amz-target"].to_str().unwrap().to_string();
+            let request: Value = serde_json::from_str(&body).unwrap();
+            let name = request["SecretId"]
+                .as_str()
+                .or(request["Name"].as_str())
+                .unwrap()
+                .to_string();
+            stub.requests
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", target, name));
+            let Some(value) = stub.values.lock().unwrap().get(&name).cloned() else {
+                let error = json!({"__type": "ResourceNotFoundException", "message": "not found"});
+                return (StatusCode::BAD_REQUEST, error.to_string()).into_response();
+            };
+            let response = match target.as_str() {
+                "secretsmanager.GetSecretValue" => json!({"Name": name, "SecretString": value}),
+                "AmazonSSM.GetParameter" => {
+                    json!({"Parameter": {"Name": name, "Type": "SecureString", "Value": value}})
+                }
+                other => panic!("unexpected operation {}", other),
+            };
+            let headers = [("content-type", "application/x-amz-json-1.1")];
+            (headers, response.to_string()).into_response()
+        }
+    }
+
+    fn secrets(endpoint: &str) -> Secrets {
+        let credentials = Credentials::new("AKID", "secret", None, None, "test");
+        let secrets_manager = aws_sdk_secretsmanager::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(credentials.clone())
+            .endpoint_url(endpoint)
+            .build();
+        let ssm = aws_sdk_ssm::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(aws_sdk_ssm::config::Region::new("us-east-1"))
+            .credentials_provider(credentials)
+            .endpoint_url(endpoint)
+            .build();
+        Secrets {
+            clients: OnceCell::new_with(Some(Clients {
+                secrets_manager: aws_sdk_secretsmanager::Client::from_conf(secrets_manager),
+                ssm: aws_sdk_ssm::Client::from_conf(ssm),
+            })),
+            ..Secrets::default()
+        }
+    }
+
+    fn parse(value: &str) -> Option<Result<SecretRef, String>> {
+        SecretRef::parse(value)
+    }
+
+    #[test]
+    fn parses_secrets_manager_references() {
+        assert_eq!(
+            parse("secretsmanager:otel/vendor"),
+            Some(Ok(SecretRef::SecretsManager {
+                id: "otel/vendor".to_string(),
+                key: None,
+            }))
+        );
+        assert_eq!(
+            parse(" secretsmanager:arn:aws:secretsmanager:us-east-1:1:secret:otel#token "),
+            Some(Ok(SecretRef::SecretsManager {
+                id: "arn:aws:secretsmanager:us-east-1:1:secret:otel".to_string(),
+                key: Some("token".to_string()),
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_parameter_references() {
+        assert_eq!(
+            parse("ssm:/otel/api-key"),
+            Some(Ok(SecretRef::Parameter {
+                name: "/otel/api-key".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_references() {
+        for value in [
+            "secretsmanager:",
+            "secretsmanager:#token",
+            "secretsmanager:id#",
+            "ssm:",
+        ] {
+            assert!(
+                matches!(parse(value), Some(Err(ref e)) if e.contains("is not a valid reference")),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_other_values_alone() {
+        for value in [
+            "Bearer abc",
+            "",
+            "SSM:/otel",
+            "https://ssm:443",
+            "secrets:x",
+        ] {
+            assert_eq!(parse(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn displays_references_as_written() {
+        for value in [
+            "secretsmanager:otel",
+            "secretsmanager:otel#token",
+            "ssm:/otel/key",
+        ] {
+            assert_eq!(parse(value).unwrap().unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn reads_keys_of_json_secrets() {
+        let secret = r#"{"token": "abc", "port": 4317}"#;
+        assert_eq!(json_field(secret, "token").as_deref(), Some("abc"));
+        assert_eq!(json_field(secret, "port").as_deref(), Some("4317"));
+        assert_eq!(json_field(secret, "missing"), None);
+        assert_eq!(json_field("plain", "token"), None);
+    }
+
+    #[tokio::test]
+    async fn fetches_from_both_services_once() {
+        let stub = Stub::default();
+        stub.set("otel/vendor", r#"{"token": "from-secrets-manager"}"#);
+        stub.set("/otel/key", "from-ssm");
+        let secrets = secrets(&stub.serve().await);
+        let vendor = parse("secretsmanager:otel/vendor#token").unwrap().unwrap();
+        let key = parse("ssm:/otel/key").unwrap().unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(
+                secrets.resolve(&vendor).await.unwrap(),
+                "from-secrets-manager"
+            );
+            assert_eq!(secrets.resolve(&key).await.unwrap(), "from-ssm");
+        }
+        assert_eq!(
+            stub.requests(),
+            [
+                "secretsmanager.GetSecretValue otel/vendor",
+                "AmazonSSM.GetParameter /otel/key",
+            ]
+        );
+
+        let missing = parse("ssm:/otel/missing").unwrap().unwrap();
+        let error = secrets.resolve(&missing).await.unwrap_err();
+        assert!(
+            error.starts_with("cannot read ssm:/otel/missing"),
+            "{}",
+            error
+        );
+        let no_key = parse("secretsmanager:otel/vendor#user").unwrap().unwrap();
+        assert_eq!(
+            secrets.resolve(&no_key).await.unwrap_err(),
+            "secretsmanager:otel/vendor#user has no \"user\" key"
+        );
+    }
+
+    #[tokio::test]
+    async fn refreshes_at_most_once_a_minute() {
+        let stub = Stub::default();
+        stub.set("/otel/key", "first");
+        let secrets: &'static Secrets = Box::leak(Box::new(secrets(&stub.serve().await)));
+        let key = parse("ssm:/otel/key").unwrap().unwrap();
+        assert_eq!(secrets.resolve(&key).await.unwrap(), "first");
+
+        let refreshed_to = |value: &'static str| {
+            let key = key.clone();
+            async move {
+                for _ in 0..100 {
+                    if secrets.cached(&key).as_deref() == Some(value) {
+                        return true;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                false
+            }
+        };
+        stub.set("/otel/key", "rotated");
+        secrets.refresh();
+        assert!(refreshed_to("rotated").await);
+        assert_eq!(stub.requests().len(), 2);
+
+        stub.set("/otel/key", "rotated again");
+        secrets.refresh();
+        assert!(!refreshed_to("rotated again").await);
+        assert_eq!(stub.requests().len(), 2);
+
+        // Once the interval has passed it is fetched again.
+        *secrets.last_refresh.lock().unwrap() =
+            Some(Instant::now() - MIN_REFRESH_INTERVAL - Duration::from_secs(1));
+        secrets.refresh();
+        assert!(refreshed_to("rotated again").await);
+        assert_eq!(stub.requests().len(), 3);
+    }
+}
@@ -0,0 +1,131 @@
+//! Mapping from Lambda Telemetry API fields onto OpenTelemetry semantic
+//! conventions. Lambda-specific values with no convention of their own live
+//! under the `aws.lambda.` namespace.
+
+use lambda_extension::{InitPhase, InitType, ReportMetrics, RuntimeDoneMetrics, Status};
+use opentelemetry::KeyValue;
+
+pub const CLOUD_PROVIDER: &str = "cloud.provider";
+pub const CLOUD_PLATFORM: &str = "cloud.platform";
+pub const CLOUD_REGION: &str = "cloud.region";
+pub const FAAS_NAME: &str = "faas.name";
+pub const FAAS_VERSION: &str = "faas.version";
+pub const FAAS_INSTANCE: &str = "faas.instance";
+pub const FAAS_MAX_MEMORY: &str = "faas.max_memory";
+pub const FAAS_INVOCATION_ID: &str = "faas.invocation_id";
+pub const FAAS_COLDSTART: &str = "faas.coldstart";
+pub const FAAS_TRIGGER: &str = "faas.trigger";
+pub const ERROR_TYPE: &str = "error.type";
+pub const CODE_NAMESPACE: &str = "code.namespace";
+pub const THREAD_NAME: &str = "thread.name";
+pub const AWS_LOG_GROUP_NAMES: &str = "aws.log.group.names";
+pub const AWS_LOG_STREAM_NAMES: &str = "aws.log.stream.names";
+
+pub const AWS_LAMBDA_STATUS: &str = "aws.lambda.status";
+pub const AWS_LAMBDA_DURATION_MS: &str = "aws.lambda.duration_ms";
+pub const AWS_LAMBDA_BILLED_DURATION_MS: &str = "aws.lambda.billed_duration_ms";
+pub const AWS_LAMBDA_MAX_MEMORY_USED: &str = "aws.lambda.max_memory_used";
+pub const AWS_LAMBDA_INIT_DURATION_MS: &str = "aws.lambda.init_duration_ms";
+pub const AWS_LAMBDA_RESTORE_DURATION_MS: &str = "aws.lambda.restore_duration_ms";
+pub const AWS_LAMBDA_RUNTIME_DURATION_MS: &str = "aws.lambda.runtime_duration_ms";
+pub const AWS_LAMBDA_PRODUCED_BYTES: &str = "aws.lambda.produced_bytes";
+pub const AWS_LAMBDA_INITIALIZATION_TYPE: &str = "aws.lambda.initialization_type";
+pub const AWS_LAMBDA_INIT_PHASE: &str = "aws.lambda.init.phase";
+pub const AWS_LAMBDA_RUNTIME_VERSION: &str = "aws.lambda.runtime_version";
+pub const AWS_LAMBDA_RUNTIME_VERSION_ARN: &str = "aws.lambda.runtime_version_arn";
+pub const AWS_LAMBDA_LOG_TYPE: &str = "aws.lambda.log.type";
+pub const AWS_LAMBDA_EXECUTION_ENV: &str = "aws.lambda.execution_env";
+pub const AWS_LAMBDA_SPAN_DURATION_MS: &str = "aws.lambda.span.duration_ms";
+pub const AWS_LAMBDA_SHUTDOWN_REASON: &str = "aws.lambda.shutdown.reason";
+pub const AWS_LAMBDA_UNFINISHED_SPANS: &str = "aws.lambda.unfinished_spans";
+
+const BYTES_PER_MB: i64 = 1024 * 1024;
+
+/// Attributes set when an invocation span is opened.
+pub fn invocation_attributes(request_id: &str, coldstart: bool) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new(FAAS_INVOCATION_ID, request_id.to_string()),
+        // The Telemetry API does not say what triggered the invocation.
+        KeyValue::new(FAAS_TRIGGER, "other"),
+        KeyValue::new(FAAS_COLDSTART, coldstart),
+    ]
+}
+
+pub fn runtime_done_attributes(metrics: &RuntimeDoneMetrics) -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new(
+        AWS_LAMBDA_RUNTIME_DURATION_MS,
+        metrics.duration_ms,
+    )];
+    if let Some(produced_bytes) = metrics.produced_bytes {
+        attributes.push(KeyValue::new(
+            AWS_LAMBDA_PRODUCED_BYTES,
+            produced_bytes as i64,
+        ));
+    }
+    attributes
+}
+
+pub fn report_attributes(metrics: &ReportMetrics) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(AWS_LAMBDA_DURATION_MS, metrics.duration_ms),
+        KeyValue::new(
+            AWS_LAMBDA_BILLED_DURATION_MS,
+            metrics.billed_duration_ms as i64,
+        ),
+        KeyValue::new(FAAS_MAX_MEMORY, mb_to_bytes(metrics.memory_size_mb)),
+        KeyValue::new(
+            AWS_LAMBDA_MAX_MEMORY_USED,
+            mb_to_bytes(metrics.max_memory_used_mb),
+        ),
+    ];
+    if let Some(init_duration_ms) = metrics.init_duration_ms {
+        attributes.push(KeyValue::new(AWS_LAMBDA_INIT_DURATION_MS, init_duration_ms));
+    }
+    if let Some(restore_duration_ms) = metrics.restore_duration_ms {
+        attributes.push(KeyValue::new(
+            AWS_LAMBDA_RESTORE_DURATION_MS,
+            restore_duration_ms,
+        ));
+    }
+    attributes
+}
+
+pub fn init_attributes(initialization_type: &InitType, phase: Option<&InitPhase>) -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new(
+        AWS_LAMBDA_INITIALIZATION_TYPE,
+        init_type_name(initialization_type),
+    )];
+    if let Some(phase) = phase {
+        attributes.push(KeyValue::new(AWS_LAMBDA_INIT_PHASE, init_phase_name(phase)));
+    }
+    attributes
+}
+
+/// `faas.max_memory` and friends are expressed in bytes.
+pub fn mb_to_bytes(mb: u64) -> i64 {
+    (mb as i64).saturating_mul(BYTES_PER_MB)
+}
+
+pub fn status_name(status: &Status) -> &'static str {
+    match status {
+        Status::Success => "success",
+        Status::Error => "error",
+        Status::Failure => "failure",
+        Status::Timeout => "timeout",
+    }
+}
+
+pub fn init_type_name(initialization_type: &InitType) -> &'static str {
+    match initialization_type {
+        InitType::OnDemand => "on-demand",
+        InitType::ProvisionedConcurrency => "provisioned-concurrency",
+        InitType::SnapStart => "snap-start",
+    }
+}
+
+pub fn init_phase_name(phase: &InitPhase) -> &'static str {
+    match phase {
+        InitPhase::Init => "init",
+        InitPhase::Invoke => "invoke",
+    }
+}
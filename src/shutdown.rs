@@ -0,0 +1,70 @@
+use crate::flush::remaining;
+use crate::invocation::InvocationTracker;
+use crate::logs::LogEmitter;
+use crate::pipeline::Pipelines;
+use lambda_extension::ShutdownEvent;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// Kept back from the SHUTDOWN deadline so the process is done before Lambda
+// kills it.
+const EXIT_MARGIN: Duration = Duration::from_millis(100);
+
+/// The time Lambda gives extensions after SHUTDOWN, used when the extension
+/// stops without one.
+pub const DEFAULT_BUDGET: Duration = Duration::from_secs(2);
+
+/// Ends open spans and drains every pipeline before the environment goes
+/// away.
+pub struct ShutdownCoordinator {
+    tracker: Arc<Mutex<InvocationTracker>>,
+    log_emitter: Arc<LogEmitter>,
+    pipelines: Pipelines,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(
+        tracker: Arc<Mutex<InvocationTracker>>,
+        log_emitter: Arc<LogEmitter>,
+        pipelines: Pipelines,
+    ) -> Self {
+        ShutdownCoordinator {
+            tracker,
+            log_emitter,
+            pipelines,
+        }
+    }
+
+    /// Handles a SHUTDOWN event, draining within its deadline.
+    pub async fn on_shutdown(&self, event: &ShutdownEvent) {
+        let reason = event.shutdown_reason.to_ascii_lowercase();
+        let budget = remaining(event.deadline_ms).saturating_sub(EXIT_MARGIN);
+        self.shutdown(&reason, budget).await;
+    }
+
+    /// Ends open spans, records `reason` and exports everything still
+    /// buffered, giving up after `budget`.
+    pub async fn shutdown(&self, reason: &str, budget: Duration) {
+        match reason {
+            "spindown" => info!("Shutting down ({}), draining within {:?}", reason, budget),
+            _ => warn!("Shutting down ({}), draining within {:?}", reason, budget),
+        }
+        let started = Instant::now();
+
+        let unfinished_spans = self.tracker.lock().unwrap().end_all();
+        self.log_emitter.emit_shutdown(reason, unfinished_spans);
+
+        let failed = self.pipelines.shutdown(budget).await;
+        if failed.is_empty() {
+            info!("Drained all telemetry in {:?}", started.elapsed());
+        } else {
+            let pipelines: Vec<String> = failed.iter().map(ToString::to_string).collect();
+            error!(
+                "Could not send all {} before shutdown ({} spans were ended without a report)",
+                pipelines.join(", "),
+                unfinished_spans
+            );
+        }
+    }
+}
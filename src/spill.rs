@@ -0,0 +1,617 @@
+//! An optional buffer on the ephemeral storage for HTTP exports. Spans and
+//! logs that are still unsent when the extension shuts down are encoded as
+//! they would have been sent and written to disk, then replayed at the next
+//! INIT of the same execution environment, or by the next export if the
+//! collector is still unreachable then.
+//!
+//! Each batch is a segment file with a checksum, written under a temporary
+//! name and renamed into place, so one cut short when the environment was
+//! frozen or killed is recognised and discarded.
+
+use crate::exporter::{self, ExporterConfig};
+use crate::flush::Signal;
+use crate::retry;
+use crate::secrets;
+use http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use http::{HeaderMap, StatusCode};
+use opentelemetry_http::Request;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::{debug, info, warn};
+
+const MAGIC: &[u8; 8] = b"MFSPILL1";
+const SEGMENT_EXTENSION: &str = "seg";
+const PARTIAL_EXTENSION: &str = "tmp";
+
+/// Where unsent batches are kept, and how much of the ephemeral storage
+/// they may take.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpillSettings {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    /// Shared by every destination. The oldest segments are dropped first.
+    pub max_bytes: u64,
+    /// Segments older than this are dropped instead of replayed.
+    pub max_age_secs: u64,
+}
+
+impl Default for SpillSettings {
+    fn default() -> Self {
+        SpillSettings {
+            enabled: false,
+            directory: PathBuf::from("/tmp/microfiber-spill"),
+            max_bytes: 64 * 1024 * 1024,
+            max_age_secs: 3_600,
+        }
+    }
+}
+
+impl SpillSettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.enabled {
+            return problems;
+        }
+        if !self.directory.is_absolute() {
+            problems.push(format!(
+                "spill.directory is {:?}, expected an absolute path",
+                self.directory
+            ));
+        }
+        if self.max_bytes == 0 {
+            problems.push("spill.max_bytes must be greater than 0".to_string());
+        }
+        if self.max_age_secs == 0 {
+            problems.push("spill.max_age_secs must be greater than 0".to_string());
+        }
+        problems
+    }
+}
+
+tokio::task_local! {
+    static CAPTURING: ();
+}
+
+/// Runs `export` with its HTTP requests written to the spool rather than
+/// sent, blocking until it has finished. `None` when that is not possible
+/// from the current thread.
+pub fn capture<F: Future>(export: F) -> Option<F::Output> {
+    let handle = Handle::try_current().ok()?;
+    if handle.runtime_flavor() != RuntimeFlavor::MultiThread {
+        return None;
+    }
+    Some(tokio::task::block_in_place(|| {
+        handle.block_on(CAPTURING.scope((), export))
+    }))
+}
+
+/// Whether requests are being written to the spool by [`capture`].
+pub fn capturing() -> bool {
+    CAPTURING.try_with(|_| ()).is_ok()
+}
+
+/// The spilled batches of one signal to one destination.
+#[derive(Debug)]
+pub struct Spool {
+    name: String,
+    root: PathBuf,
+    directory: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    endpoint: String,
+    headers: BTreeMap<String, String>,
+    secret_headers: BTreeMap<String, secrets::SecretRef>,
+    timeout: Duration,
+    client: reqwest::Client,
+    // Whether there may be segments to replay, so exports do not list the
+    // directory every time.
+    pending: AtomicBool,
+    replaying: tokio::sync::Mutex<()>,
+    sequence: AtomicU32,
+}
+
+impl Spool {
+    /// The spool for `exporter`, if spilling is enabled and applies to it.
+    /// Metrics are not spilled, since the next export carries everything a
+    /// lost one would have, and gRPC requests are encoded out of reach.
+    pub fn open(settings: &SpillSettings, exporter: &ExporterConfig) -> Option<Arc<Spool>> {
+        if !settings.enabled || !exporter.protocol.is_http() || exporter.signal == Signal::Metrics {
+            return None;
+        }
+        let directory = settings
+            .directory
+            .join(file_name(&exporter.destination))
+            .join(exporter.signal.to_string());
+        if let Err(e) = fs::create_dir_all(&directory) {
+            warn!(
+                "Cannot create {}, not spilling {} to {}: {}",
+                directory.display(),
+                exporter.signal,
+                exporter.destination,
+                e
+            );
+            return None;
+        }
+        let spool = Spool {
+            name: format!("{} to {}", exporter.signal, exporter.destination),
+            root: settings.directory.clone(),
+            directory,
+            max_bytes: settings.max_bytes,
+            max_age: Duration::from_secs(settings.max_age_secs),
+            endpoint: exporter.endpoint.clone(),
+            headers: exporter.headers.clone(),
+            secret_headers: exporter.secret_headers.clone(),
+            timeout: exporter.timeout,
+            client: reqwest::Client::new(),
+            pending: AtomicBool::new(false),
+            replaying: tokio::sync::Mutex::new(()),
+            sequence: AtomicU32::new(0),
+        };
+        // Partial files are left by an environment that stopped mid-write.
+        for path in list(&spool.directory, PARTIAL_EXTENSION) {
+            debug!("Removing the partly written {}", path.display());
+            remove(&path);
+        }
+        let segments = list(&spool.directory, SEGMENT_EXTENSION).len();
+        if segments > 0 {
+            info!("Found {} spilled batch(es) of {}", segments, spool.name);
+            spool.pending.store(true, Ordering::SeqCst);
+        }
+        Some(Arc::new(spool))
+    }
+
+    /// Writes the body of an encoded export request as a new segment,
+    /// dropping the oldest segments if the spill directory would outgrow
+    /// its limit.
+    pub fn write(&self, request: &Request<Vec<u8>>) -> io::Result<()> {
+        let segment = Segment {
+            created_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            content_type: header(request.headers(), CONTENT_TYPE.as_str()),
+            content_encoding: header(request.headers(), CONTENT_ENCODING.as_str()),
+            body: request.body().clone(),
+        };
+        let encoded = segment.encode();
+        let size = encoded.len() as u64;
+        if size > self.max_bytes {
+            warn!(
+                "A {} byte batch of {} exceeds the {} byte spill limit, dropping it",
+                size, self.name, self.max_bytes
+            );
+            return Ok(());
+        }
+        self.make_room(size);
+
+        let name = format!(
+            "{:020}-{}-{:010}",
+            segment.created_ms,
+            std::process::id(),
+            self.sequence.fetch_add(1, Ordering::SeqCst)
+        );
+        let partial = self
+            .directory
+            .join(format!("{}.{}", name, PARTIAL_EXTENSION));
+        fs::write(&partial, &encoded)?;
+        fs::rename(
+            &partial,
+            self.directory
+                .join(format!("{}.{}", name, SEGMENT_EXTENSION)),
+        )?;
+        self.pending.store(true, Ordering::SeqCst);
+        debug!("Spilled a {} byte batch of {}", size, self.name);
+        Ok(())
+    }
+
+    /// Sends the spilled batches, oldest first, stopping at the first one
+    /// the collector should be asked for again later.
+    pub async fn replay(&self) {
+        if !self.pending.load(Ordering::SeqCst) {
+            return;
+        }
+        let Ok(_replaying) = self.replaying.try_lock() else {
+            return;
+        };
+        let started = Instant::now();
+        // Under a flush or shutdown deadline, replaying takes at most half
+        // of the time left, so the export it runs ahead of keeps the rest.
+        let until = retry::deadline()
+            .map(|deadline| started + deadline.saturating_duration_since(started) / 2);
+        let mut sent = 0;
+        let mut pending = false;
+        for path in list(&self.directory, SEGMENT_EXTENSION) {
+            if until.is_some_and(|until| Instant::now() >= until) {
+                pending = true;
+                break;
+            }
+            let segment = match fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| Segment::decode(&data))
+            {
+                Ok(segment) => segment,
+                Err(e) => {
+                    warn!("Discarding the spilled {}: {}", path.display(), e);
+                    remove(&path);
+                    continue;
+                }
+            };
+            if segment.age() > self.max_age {
+                warn!(
+                    "Discarding the spilled {}, which is older than {:?}",
+                    path.display(),
+                    self.max_age
+                );
+                remove(&path);
+                continue;
+            }
+            match self.send(segment, until).await {
+                Ok(()) => {
+                    remove(&path);
+                    sent += 1;
+                }
+                Err(Rejected::Permanently(e)) => {
+                    warn!("Discarding the spilled {}: {}", path.display(), e);
+                    remove(&path);
+                }
+                Err(Rejected::ForNow(e)) => {
+                    debug!("Could not replay {} yet: {}", self.name, e);
+                    pending = true;
+                    break;
+                }
+            }
+        }
+        self.pending.store(pending, Ordering::SeqCst);
+        if sent > 0 {
+            info!(
+                "Replayed {} spilled batch(es) of {} in {:?}",
+                sent,
+                self.name,
+                started.elapsed()
+            );
+        }
+    }
+
+    async fn send(&self, segment: Segment, until: Option<Instant>) -> Result<(), Rejected> {
+        let timeout = match until {
+            Some(until) => self
+                .timeout
+                .min(until.saturating_duration_since(Instant::now())),
+            None => self.timeout,
+        };
+        let mut headers = HeaderMap::new();
+        exporter::insert_headers(&mut headers, &self.headers);
+        exporter::insert_headers(
+            &mut headers,
+            &exporter::current_values(&self.secret_headers),
+        );
+        let mut request = self
+            .client
+            .post(&self.endpoint)
+            .timeout(timeout)
+            .headers(headers)
+            .header(CONTENT_TYPE, segment.content_type);
+        if !segment.content_encoding.is_empty() {
+            request = request.header(CONTENT_ENCODING, segment.content_encoding);
+        }
+        let response = request
+            .body(segment.body)
+            .send()
+            .await
+            .map_err(|e| Rejected::ForNow(e.to_string()))?;
+        let status = response.status();
+        match status {
+            _ if status.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                if !self.secret_headers.is_empty() {
+                    secrets::global().refresh();
+                }
+                Err(Rejected::ForNow(format!(
+                    "the collector responded with {}",
+                    status
+                )))
+            }
+            _ if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => Err(
+                Rejected::ForNow(format!("the collector responded with {}", status)),
+            ),
+            _ => Err(Rejected::Permanently(format!(
+                "the collector responded with {}",
+                status
+            ))),
+        }
+    }
+
+    // Drops the oldest segments of every destination until `incoming` more
+    // bytes fit within the limit.
+    fn make_room(&self, incoming: u64) {
+        let mut segments: Vec<(String, PathBuf, u64)> = subdirectories(&self.root)
+            .into_iter()
+            .flat_map(|destination| subdirectories(&destination))
+            .flat_map(|directory| list(&directory, SEGMENT_EXTENSION))
+            .filter_map(|path| {
+                let size = fs::metadata(&path).ok()?.len();
+                let name = path.file_name()?.to_string_lossy().into_owned();
+                Some((name, path, size))
+            })
+            .collect();
+        let mut total: u64 = segments.iter().map(|(_, _, size)| size).sum();
+        if total + incoming <= self.max_bytes {
+            return;
+        }
+        // Names start with the creation time.
+        segments.sort();
+        let mut dropped = 0;
+        for (_, path, size) in segments {
+            if total + incoming <= self.max_bytes {
+                break;
+            }
+            remove(&path);
+            total -= size;
+            dropped += 1;
+        }
+        warn!(
+            "Dropped {} spilled batch(es) to stay within {} bytes",
+            dropped, self.max_bytes
+        );
+    }
+}
+
+enum Rejected {
+    /// The collector may take the batch later.
+    ForNow(String),
+    Permanently(String),
+}
+
+/// One spilled request body and the headers needed to send it again.
+/// Credentials are not written to disk; they are added when replaying.
+struct Segment {
+    created_ms: u64,
+    content_type: String,
+    content_encoding: String,
+    body: Vec<u8>,
+}
+
+impl Segment {
+    // The magic number, the creation time, a CRC-32 of the rest, then the
+    // content type, content encoding and body, each after its length.
+    fn encode(&self) -> Vec<u8> {
+        let mut rest = Vec::new();
+        for field in [
+            self.content_type.as_bytes(),
+            self.content_encoding.as_bytes(),
+            &self.body,
+        ] {
+            rest.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            rest.extend_from_slice(field);
+        }
+        let mut data = Vec::with_capacity(MAGIC.len() + 12 + rest.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.created_ms.to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&rest).to_le_bytes());
+        data.extend_from_slice(&rest);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<Segment, String> {
+        let mut reader = Reader(data);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("not a spill segment".to_string());
+        }
+        let created_ms = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        let checksum = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        if crc32fast::hash(reader.0) != checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        let content_type = reader.field()?;
+        let content_encoding = reader.field()?;
+        let body = reader.field()?.to_vec();
+        if !reader.0.is_empty() {
+            return Err("trailing data".to_string());
+        }
+        Ok(Segment {
+            created_ms,
+            content_type: String::from_utf8_lossy(content_type).into_owned(),
+            content_encoding: String::from_utf8_lossy(content_encoding).into_owned(),
+            body,
+        })
+    }
+
+    fn age(&self) -> Duration {
+        let created = UNIX_EPOCH + Duration::from_millis(self.created_ms);
+        SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default()
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("truncated".to_string());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> Result<&'a [u8], String> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(len as usize)
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Destination names are chosen freely, so anything unusual becomes `_`, as
+// do the dots of a name that would otherwise be `.`, `..` or empty and so
+// lead out of the spill directory.
+fn file_name(destination: &str) -> String {
+    let name: String = destination
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    if name.chars().all(|c| c == '.') {
+        return "_".repeat(name.len().max(1));
+    }
+    name
+}
+
+// The files in `directory` with `extension`, sorted by name.
+fn list(directory: &Path, extension: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == extension))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn subdirectories(directory: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+// Another spool may have removed the file while making room.
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Cannot remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment() -> Segment {
+        Segment {
+            created_ms: 1_700_000_000_123,
+            content_type: "application/x-protobuf".to_string(),
+            content_encoding: "gzip".to_string(),
+            body: vec![1, 2, 3, 0, 255],
+        }
+    }
+
+    fn decode_error(data: &[u8]) -> String {
+        match Segment::decode(data) {
+            Ok(_) => panic!("decoded a corrupted segment"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn segments_round_trip() {
+        let decoded = Segment::decode(&segment().encode()).unwrap();
+        assert_eq!(decoded.created_ms, 1_700_000_000_123);
+        assert_eq!(decoded.content_type, "application/x-protobuf");
+        assert_eq!(decoded.content_encoding, "gzip");
+        assert_eq!(decoded.body, vec![1, 2, 3, 0, 255]);
+    }
+
+    #[test]
+    fn empty_fields_round_trip() {
+        let empty = Segment {
+            created_ms: 0,
+            content_type: String::new(),
+            content_encoding: String::new(),
+            body: Vec::new(),
+        };
+        let decoded = Segment::decode(&empty.encode()).unwrap();
+        assert_eq!(decoded.content_type, "");
+        assert_eq!(decoded.content_encoding, "");
+        assert!(decoded.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_partly_written_segments() {
+        let data = segment().encode();
+        for len in 0..data.len() {
+            assert!(Segment::decode(&data[..len]).is_err(), "length {}", len);
+        }
+        assert_eq!(decode_error(&data[..4]), "truncated");
+    }
+
+    #[test]
+    fn rejects_a_checksum_mismatch() {
+        let mut data = segment().encode();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert_eq!(decode_error(&data), "checksum mismatch");
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut data = segment().encode();
+        data.push(0);
+        // Appended after the checksum was taken, and with a matching one.
+        assert_eq!(decode_error(&data), "checksum mismatch");
+        let checksum = crc32fast::hash(&data[20..]);
+        data[16..20].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(decode_error(&data), "trailing data");
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_the_data() {
+        let mut data = segment().encode();
+        data[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        let checksum = crc32fast::hash(&data[20..]);
+        data[16..20].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(decode_error(&data), "truncated");
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut data = segment().encode();
+        data[0] = b'X';
+        assert_eq!(decode_error(&data), "not a spill segment");
+        assert_eq!(
+            decode_error(b"{\"resourceSpans\":[]}"),
+            "not a spill segment"
+        );
+    }
+
+    #[test]
+    fn file_names_stay_inside_the_spill_directory() {
+        assert_eq!(file_name("honeycomb.eu-1"), "honeycomb.eu-1");
+        assert_eq!(file_name("a/b c"), "a_b_c");
+        assert_eq!(file_name("../../etc"), ".._.._etc");
+        assert_eq!(file_name("."), "_");
+        assert_eq!(file_name(".."), "__");
+        assert_eq!(file_name("..."), "___");
+        assert_eq!(file_name(""), "_");
+        for name in ["..", "../..", "/", "a/../.."] {
+            let path = Path::new("/tmp/spill").join(file_name(name));
+            assert_eq!(path.parent(), Some(Path::new("/tmp/spill")), "{:?}", name);
+            assert!(!path.ends_with(".."), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Joins the spans the function exports to the invocation or init span the
+//! extension builds from platform events, so one trace shows platform
+//! overhead, init and handler work together.
+
+use crate::invocation::Correlation;
+use opentelemetry::trace::{Link, SpanContext, SpanId, TraceId};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::logs::TraceContext;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use tracing::debug;
+
+// Traces moved into an invocation's trace, remembered for spans and logs of
+// theirs that arrive in later requests.
+const MAX_MOVED_TRACES: usize = 256;
+
+/// How spans from the function are joined to their invocation.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stitching {
+    /// The function's root spans become children of the invocation span,
+    /// and traces the function started itself move into its trace.
+    #[default]
+    Parent,
+    /// The function's root spans get a link to the invocation span.
+    Link,
+    /// Spans are exported as received.
+    Off,
+}
+
+/// Stitches spans received from the function, remembering which of the
+/// traces it started were moved into an invocation's trace.
+#[derive(Debug, Default)]
+pub struct Stitcher {
+    stitching: Stitching,
+    moved: HashMap<TraceId, SpanContext>,
+    order: VecDeque<TraceId>,
+}
+
+impl Stitcher {
+    pub fn new(stitching: Stitching) -> Self {
+        Stitcher {
+            stitching,
+            ..Default::default()
+        }
+    }
+
+    /// Joins each span to the invocation it was correlated with, given in
+    /// the same order.
+    ///
+    /// A trace is only moved when its root is among `spans`, since a span
+    /// whose parent was not seen may belong to a trace started by a caller.
+    pub fn stitch(&mut self, spans: &mut [SpanData], correlations: &[Option<Correlation>]) {
+        if self.stitching == Stitching::Off {
+            return;
+        }
+        let started: HashSet<TraceId> = spans
+            .iter()
+            .filter(|span| span.parent_span_id == SpanId::INVALID)
+            .map(|span| span.span_context.trace_id())
+            .collect();
+        for (span, correlation) in spans.iter_mut().zip(correlations) {
+            let trace_id = span.span_context.trace_id();
+            if let Some(invocation) = self.moved.get(&trace_id) {
+                move_span(span, invocation);
+                continue;
+            }
+            let Some(correlation) = correlation else {
+                continue;
+            };
+            let invocation = &correlation.span_context;
+            let same_trace = trace_id == invocation.trace_id();
+            let root = span.parent_span_id == SpanId::INVALID
+                || (same_trace
+                    && correlation.parent_span_id != SpanId::INVALID
+                    && span.parent_span_id == correlation.parent_span_id);
+            match self.stitching {
+                Stitching::Parent if same_trace && root => {
+                    span.parent_span_id = invocation.span_id();
+                }
+                Stitching::Parent if !same_trace && started.contains(&trace_id) => {
+                    debug!(
+                        "Moving trace {} into the invocation's trace {}",
+                        trace_id,
+                        invocation.trace_id()
+                    );
+                    self.remember(trace_id, invocation.clone());
+                    move_span(span, invocation);
+                }
+                Stitching::Link if root => {
+                    span.links
+                        .links
+                        .push(Link::with_context(invocation.clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Moves a log record's trace context along with its trace.
+    pub fn stitch_log(&self, trace_context: &mut TraceContext) {
+        if let Some(invocation) = self.moved.get(&trace_context.trace_id) {
+            trace_context.trace_id = invocation.trace_id();
+        }
+    }
+
+    fn remember(&mut self, trace_id: TraceId, invocation: SpanContext) {
+        if self.moved.len() >= MAX_MOVED_TRACES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.moved.remove(&oldest);
+            }
+        }
+        self.moved.insert(trace_id, invocation);
+        self.order.push_back(trace_id);
+    }
+}
+
+// Puts a span of a moved trace in the invocation's trace, with its root
+// under the invocation span.
+fn move_span(span: &mut SpanData, invocation: &SpanContext) {
+    let context = &span.span_context;
+    span.span_context = SpanContext::new(
+        invocation.trace_id(),
+        context.span_id(),
+        context.trace_flags(),
+        false,
+        context.trace_state().clone(),
+    );
+    if span.parent_span_id == SpanId::INVALID {
+        span.parent_span_id = invocation.span_id();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanKind, Status, TraceFlags, TraceState};
+    use opentelemetry::InstrumentationLibrary;
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+    use std::borrow::Cow;
+    use std::time::SystemTime;
+
+    const INVOCATION_TRACE: u128 = 0xaaaa;
+    const FUNCTION_TRACE: u128 = 0xbbbb;
+    const INVOCATION_SPAN: u64 = 0x10;
+    const XRAY_PARENT: u64 = 0x20;
+
+    fn context(trace_id: u128, span_id: u64) -> SpanContext {
+        SpanContext::new(
+            TraceId::from(trace_id),
+            SpanId::from(span_id),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        )
+    }
+
+    fn span(trace_id: u128, span_id: u64, parent_span_id: u64) -> SpanData {
+        SpanData {
+            span_context: context(trace_id, span_id),
+            parent_span_id: SpanId::from(parent_span_id),
+            span_kind: SpanKind::Internal,
+            name: Cow::Borrowed("handler"),
+            start_time: SystemTime::UNIX_EPOCH,
+            end_time: SystemTime::UNIX_EPOCH,
+            attributes: Vec::new(),
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status: Status::Unset,
+            instrumentation_lib: InstrumentationLibrary::default(),
+        }
+    }
+
+    fn invocation() -> Option<Correlation> {
+        Some(Correlation {
+            request_id: Some("req-1".to_string()),
+            span_context: context(INVOCATION_TRACE, INVOCATION_SPAN),
+            parent_span_id: SpanId::from(XRAY_PARENT),
+        })
+    }
+
+    // Stitches `spans`, all correlated with the invocation, and returns each
+    // one's trace id, parent and links.
+    fn stitch(
+        stitcher: &mut Stitcher,
+        mut spans: Vec<SpanData>,
+    ) -> Vec<(TraceId, SpanId, Vec<SpanContext>)> {
+        let correlations = vec![invocation(); spans.len()];
+        stitcher.stitch(&mut spans, &correlations);
+        spans
+            .into_iter()
+            .map(|span| {
+                let links = span
+                    .links
+                    .links
+                    .into_iter()
+                    .map(|link| link.span_context)
+                    .collect();
+                (span.span_context.trace_id(), span.parent_span_id, links)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parents_roots_in_the_invocation_trace() {
+        let mut stitcher = Stitcher::new(Stitching::Parent);
+        let stitched = stitch(
+            &mut stitcher,
+            vec![
+                span(INVOCATION_TRACE, 1, 0),
+                span(INVOCATION_TRACE, 2, XRAY_PARENT),
+                span(INVOCATION_TRACE, 3, 2),
+            ],
+        );
+        let invocation = SpanId::from(INVOCATION_SPAN);
+        let trace_id = TraceId::from(INVOCATION_TRACE);
+        assert_eq!(
+            stitched,
+            [
+                (trace_id, invocation, vec![]),
+                (trace_id, invocation, vec![]),
+                (trace_id, SpanId::from(2), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_a_trace_the_function_started_with_its_later_spans_and_logs() {
+        let mut stitcher = Stitcher::new(Stitching::Parent);
+        let stitched = stitch(
+            &mut stitcher,
+            vec![span(FUNCTION_TRACE, 1, 0), span(FUNCTION_TRACE, 2, 1)],
+        );
+        let trace_id = TraceId::from(INVOCATION_TRACE);
+        assert_eq!(
+            stitched,
+            [
+                (trace_id, SpanId::from(INVOCATION_SPAN), vec![]),
+                (trace_id, SpanId::from(1), vec![]),
+            ]
+        );
+
+        // A later request with only a child of the moved trace, and no
+        // correlation of its own, still follows it.
+        let mut later = vec![span(FUNCTION_TRACE, 3, 2)];
+        stitcher.stitch(&mut later, &[None]);
+        assert_eq!(later[0].span_context.trace_id(), trace_id);
+        assert_eq!(later[0].span_context.span_id(), SpanId::from(3));
+        assert_eq!(later[0].parent_span_id, SpanId::from(2));
+
+        let mut log = TraceContext::from(&context(FUNCTION_TRACE, 2));
+        stitcher.stitch_log(&mut log);
+        assert_eq!(log.trace_id, trace_id);
+        assert_eq!(log.span_id, SpanId::from(2));
+    }
+
+    #[test]
+    fn leaves_a_trace_whose_root_is_not_in_the_request() {
+        let mut stitcher = Stitcher::new(Stitching::Parent);
+        let stitched = stitch(&mut stitcher, vec![span(FUNCTION_TRACE, 2, 1)]);
+        assert_eq!(
+            stitched,
+            [(TraceId::from(FUNCTION_TRACE), SpanId::from(1), vec![])]
+        );
+
+        // Nor are its logs.
+        let mut log = TraceContext::from(&context(FUNCTION_TRACE, 2));
+        stitcher.stitch_log(&mut log);
+        assert_eq!(log.trace_id, TraceId::from(FUNCTION_TRACE));
+    }
+
+    #[test]
+    fn links_roots_to_the_invocation() {
+        let mut stitcher = Stitcher::new(Stitching::Link);
+        let stitched = stitch(
+            &mut stitcher,
+            vec![
+                span(FUNCTION_TRACE, 1, 0),
+                span(FUNCTION_TRACE, 2, 1),
+                span(INVOCATION_TRACE, 3, XRAY_PARENT),
+            ],
+        );
+        let invocation = context(INVOCATION_TRACE, INVOCATION_SPAN);
+        assert_eq!(
+            stitched,
+            [
+                (
+                    TraceId::from(FUNCTION_TRACE),
+                    SpanId::INVALID,
+                    vec![invocation.clone()]
+                ),
+                (TraceId::from(FUNCTION_TRACE), SpanId::from(1), vec![]),
+                (
+                    TraceId::from(INVOCATION_TRACE),
+                    SpanId::from(XRAY_PARENT),
+                    vec![invocation]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_spans_alone_when_off_or_uncorrelated() {
+        let spans = || vec![span(FUNCTION_TRACE, 1, 0), span(INVOCATION_TRACE, 2, 0)];
+        let untouched = vec![
+            (TraceId::from(FUNCTION_TRACE), SpanId::INVALID, vec![]),
+            (TraceId::from(INVOCATION_TRACE), SpanId::INVALID, vec![]),
+        ];
+        assert_eq!(
+            stitch(&mut Stitcher::new(Stitching::Off), spans()),
+            untouched
+        );
+
+        let mut stitcher = Stitcher::new(Stitching::Parent);
+        let mut uncorrelated = spans();
+        stitcher.stitch(&mut uncorrelated, &[None, None]);
+        assert_eq!(
+            uncorrelated[0].span_context.trace_id(),
+            TraceId::from(FUNCTION_TRACE)
+        );
+        assert_eq!(uncorrelated[0].parent_span_id, SpanId::INVALID);
+        assert_eq!(uncorrelated[1].parent_span_id, SpanId::INVALID);
+    }
+
+    #[test]
+    fn forgets_the_oldest_moved_traces() {
+        let mut stitcher = Stitcher::new(Stitching::Parent);
+        for trace in 1..=MAX_MOVED_TRACES as u128 + 1 {
+            stitch(&mut stitcher, vec![span(FUNCTION_TRACE + trace, 1, 0)]);
+        }
+        assert_eq!(stitcher.moved.len(), MAX_MOVED_TRACES);
+        assert!(!stitcher
+            .moved
+            .contains_key(&TraceId::from(FUNCTION_TRACE + 1)));
+        assert!(stitcher
+            .moved
+            .contains_key(&TraceId::from(FUNCTION_TRACE + 2)));
+    }
+}
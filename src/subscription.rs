@@ -0,0 +1,133 @@
+use lambda_extension::LogBuffering;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// Limits the Telemetry API enforces on the subscription's buffering.
+const TIMEOUT_MS: RangeInclusive<usize> = 25..=30_000;
+const MAX_BYTES: RangeInclusive<usize> = 262_144..=1_048_576;
+const MAX_ITEMS: RangeInclusive<usize> = 1_000..=10_000;
+
+/// A stream of events the Telemetry API can send.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TelemetryType {
+    Platform,
+    Function,
+    Extension,
+}
+
+impl TelemetryType {
+    pub fn name(self) -> &'static str {
+        match self {
+            TelemetryType::Platform => "platform",
+            TelemetryType::Function => "function",
+            TelemetryType::Extension => "extension",
+        }
+    }
+}
+
+impl FromStr for TelemetryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "platform" => Ok(TelemetryType::Platform),
+            "function" => Ok(TelemetryType::Function),
+            "extension" => Ok(TelemetryType::Extension),
+            other => Err(format!(
+                "unsupported telemetry type {:?}, expected platform, function or extension",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TelemetryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How long and how much Lambda buffers before pushing a batch of events.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buffering {
+    pub timeout_ms: usize,
+    pub max_bytes: usize,
+    pub max_items: usize,
+}
+
+impl Default for Buffering {
+    fn default() -> Self {
+        let defaults = LogBuffering::default();
+        Buffering {
+            timeout_ms: defaults.timeout_ms,
+            max_bytes: defaults.max_bytes,
+            max_items: defaults.max_items,
+        }
+    }
+}
+
+impl From<Buffering> for LogBuffering {
+    fn from(buffering: Buffering) -> Self {
+        LogBuffering {
+            timeout_ms: buffering.timeout_ms,
+            max_bytes: buffering.max_bytes,
+            max_items: buffering.max_items,
+        }
+    }
+}
+
+/// What the extension subscribes to on the Telemetry API.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Subscription {
+    pub types: Vec<TelemetryType>,
+    pub buffering: Buffering,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Subscription {
+            types: vec![TelemetryType::Platform, TelemetryType::Function],
+            buffering: Buffering::default(),
+        }
+    }
+}
+
+impl Subscription {
+    pub fn includes(&self, telemetry_type: TelemetryType) -> bool {
+        self.types.contains(&telemetry_type)
+    }
+
+    /// The type names in the form `Extension::with_telemetry_types` takes.
+    pub fn type_names(&self) -> Vec<&'static str> {
+        self.types.iter().map(|t| t.name()).collect()
+    }
+
+    /// Problems the Telemetry API would reject the subscription for.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.types.is_empty() {
+            problems.push("telemetry.types must name at least one event type".to_string());
+        }
+        for (name, value, limits) in [
+            ("timeout_ms", self.buffering.timeout_ms, TIMEOUT_MS),
+            ("max_bytes", self.buffering.max_bytes, MAX_BYTES),
+            ("max_items", self.buffering.max_items, MAX_ITEMS),
+        ] {
+            if !limits.contains(&value) {
+                problems.push(format!(
+                    "telemetry.buffering.{} is {}, expected {} to {}",
+                    name,
+                    value,
+                    limits.start(),
+                    limits.end()
+                ));
+            }
+        }
+        problems
+    }
+}
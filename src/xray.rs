@@ -0,0 +1,234 @@
+use lambda_extension::TraceContext;
+use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+use tracing::warn;
+
+/// The parts of an `X-Amzn-Trace-Id` header we need to join an X-Ray trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct XrayContext {
+    pub trace_id: TraceId,
+    pub parent_id: Option<SpanId>,
+    pub span_id: Option<SpanId>,
+    pub sampled: bool,
+}
+
+impl XrayContext {
+    pub fn from_trace_context(trace_context: &TraceContext) -> Option<Self> {
+        let mut context = parse_trace_header(&trace_context.value)?;
+        context.span_id = trace_context
+            .span_id
+            .as_deref()
+            .and_then(|span_id| SpanId::from_hex(span_id).ok())
+            .filter(|span_id| *span_id != SpanId::INVALID && Some(*span_id) != context.parent_id);
+        Some(context)
+    }
+
+    /// The remote parent for spans created inside this trace, carrying X-Ray's
+    /// sampling decision so parent-based samplers agree with the function code.
+    pub fn parent_span_context(&self) -> Option<SpanContext> {
+        let flags = if self.sampled {
+            TraceFlags::SAMPLED
+        } else {
+            TraceFlags::default()
+        };
+        self.parent_id.map(|parent_id| {
+            SpanContext::new(self.trace_id, parent_id, flags, true, TraceState::default())
+        })
+    }
+}
+
+/// Parses a header value such as
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+pub fn parse_trace_header(value: &str) -> Option<XrayContext> {
+    let mut trace_id = None;
+    let mut parent_id = None;
+    // A missing or deferred (`Sampled=?`) decision is treated as sampled.
+    let mut sampled = true;
+
+    for part in value.split(';') {
+        let Some((key, val)) = part.trim().split_once('=') else {
+            continue;
+        };
+        match key {
+            "Root" => trace_id = parse_root(val),
+            "Parent" => {
+                parent_id = SpanId::from_hex(val)
+                    .ok()
+                    .filter(|span_id| *span_id != SpanId::INVALID)
+            }
+            "Sampled" => sampled = val != "0",
+            _ => {}
+        }
+    }
+
+    match trace_id {
+        Some(trace_id) => Some(XrayContext {
+            trace_id,
+            parent_id,
+            span_id: None,
+            sampled,
+        }),
+        None => {
+            warn!("Ignoring X-Amzn-Trace-Id without a valid Root: {}", value);
+            None
+        }
+    }
+}
+
+// An X-Ray root is `1-{8 hex digit epoch}-{24 hex digit id}`, which maps
+// directly onto the 32 hex digits of a W3C trace id.
+fn parse_root(root: &str) -> Option<TraceId> {
+    let mut parts = root.split('-');
+    let (Some("1"), Some(epoch), Some(id), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if epoch.len() != 8 || id.len() != 24 {
+        return None;
+    }
+    TraceId::from_hex(&format!("{}{}", epoch, id))
+        .ok()
+        .filter(|trace_id| *trace_id != TraceId::INVALID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_extension::TracingType;
+
+    const ROOT: &str = "1-5759e988-bd862e3fe1be46a994272793";
+
+    fn trace_id() -> TraceId {
+        TraceId::from_hex("5759e988bd862e3fe1be46a994272793").unwrap()
+    }
+
+    fn span_id(hex: &str) -> SpanId {
+        SpanId::from_hex(hex).unwrap()
+    }
+
+    fn trace_context(value: &str, span_id: Option<&str>) -> TraceContext {
+        TraceContext {
+            span_id: span_id.map(str::to_string),
+            r#type: TracingType::AmznTraceId,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_a_full_header() {
+        let context =
+            parse_trace_header(&format!("Root={};Parent=53995c3f42cd8ad8;Sampled=1", ROOT))
+                .unwrap();
+        assert_eq!(
+            context,
+            XrayContext {
+                trace_id: trace_id(),
+                parent_id: Some(span_id("53995c3f42cd8ad8")),
+                span_id: None,
+                sampled: true,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_fields_in_any_order_with_spaces_and_extras() {
+        let context = parse_trace_header(&format!(
+            "Sampled=0; Lineage=a87bd80c:0 ;Parent=53995c3f42cd8ad8; Root={}",
+            ROOT
+        ))
+        .unwrap();
+        assert_eq!(context.trace_id, trace_id());
+        assert_eq!(context.parent_id, Some(span_id("53995c3f42cd8ad8")));
+        assert!(!context.sampled);
+    }
+
+    #[test]
+    fn treats_missing_or_deferred_sampling_as_sampled() {
+        let deferred = parse_trace_header(&format!("Root={};Sampled=?", ROOT)).unwrap();
+        assert!(deferred.sampled);
+        let missing = parse_trace_header(&format!("Root={}", ROOT)).unwrap();
+        assert!(missing.sampled);
+    }
+
+    #[test]
+    fn accepts_a_missing_or_invalid_parent() {
+        let missing = parse_trace_header(&format!("Root={};Sampled=1", ROOT)).unwrap();
+        assert_eq!(missing.parent_id, None);
+        assert_eq!(missing.parent_span_context(), None);
+
+        let zero = parse_trace_header(&format!("Root={};Parent=0000000000000000", ROOT)).unwrap();
+        assert_eq!(zero.parent_id, None);
+
+        let garbage = parse_trace_header(&format!("Root={};Parent=xyz", ROOT)).unwrap();
+        assert_eq!(garbage.parent_id, None);
+    }
+
+    #[test]
+    fn rejects_headers_without_a_valid_root() {
+        for value in [
+            "",
+            "Parent=53995c3f42cd8ad8;Sampled=1",
+            "Root=",
+            "Root=5759e988-bd862e3fe1be46a994272793",
+            "Root=2-5759e988-bd862e3fe1be46a994272793",
+            "Root=1-5759e98-bd862e3fe1be46a994272793",
+            "Root=1-5759e988-bd862e3fe1be46a99427279",
+            "Root=1-5759e988-bd862e3fe1be46a994272793-00",
+            "Root=1-zzzzzzzz-bd862e3fe1be46a994272793",
+            "Root=1-00000000-000000000000000000000000",
+            "Root",
+        ] {
+            assert_eq!(parse_trace_header(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn parent_span_context_is_remote_and_carries_sampling() {
+        let sampled = parse_trace_header(&format!("Root={};Parent=53995c3f42cd8ad8", ROOT))
+            .unwrap()
+            .parent_span_context()
+            .unwrap();
+        assert_eq!(sampled.trace_id(), trace_id());
+        assert_eq!(sampled.span_id(), span_id("53995c3f42cd8ad8"));
+        assert!(sampled.is_remote());
+        assert!(sampled.is_sampled());
+
+        let unsampled =
+            parse_trace_header(&format!("Root={};Parent=53995c3f42cd8ad8;Sampled=0", ROOT))
+                .unwrap()
+                .parent_span_context()
+                .unwrap();
+        assert!(!unsampled.is_sampled());
+    }
+
+    #[test]
+    fn takes_the_span_id_from_the_trace_context() {
+        let header = format!("Root={};Parent=53995c3f42cd8ad8;Sampled=1", ROOT);
+        let context =
+            XrayContext::from_trace_context(&trace_context(&header, Some("ab67c90b2e4d4f41")))
+                .unwrap();
+        assert_eq!(context.span_id, Some(span_id("ab67c90b2e4d4f41")));
+        assert_eq!(context.parent_id, Some(span_id("53995c3f42cd8ad8")));
+    }
+
+    #[test]
+    fn ignores_span_ids_that_are_missing_invalid_or_the_parent() {
+        let header = format!("Root={};Parent=53995c3f42cd8ad8;Sampled=1", ROOT);
+        for span_id in [
+            None,
+            Some("0000000000000000"),
+            Some("not-hex"),
+            Some("53995c3f42cd8ad8"),
+        ] {
+            let context =
+                XrayContext::from_trace_context(&trace_context(&header, span_id)).unwrap();
+            assert_eq!(context.span_id, None, "{:?}", span_id);
+        }
+    }
+
+    #[test]
+    fn trace_context_without_a_root_is_ignored() {
+        let context = trace_context("Parent=53995c3f42cd8ad8", Some("ab67c90b2e4d4f41"));
+        assert_eq!(XrayContext::from_trace_context(&context), None);
+    }
+}